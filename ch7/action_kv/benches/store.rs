@@ -0,0 +1,170 @@
+use action_kv::storage::MemoryStorage;
+use action_kv::{ActionKV, Options};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use std::path::PathBuf;
+use std::{env, fs, process};
+
+const KEYS: u32 = 10_000;
+const VALUE: &[u8; 100] = &[b'v'; 100];
+
+fn key(i: u32) -> Vec<u8> {
+    format!("key:{:08}", i).into_bytes()
+}
+
+/// Log of a benchmark. Every benchmark runs on both, memory shows the cost of the store itself
+/// and a file adds the file system of the temporary directory
+#[derive(Debug)]
+enum Log {
+    Memory(MemoryStorage),
+    File(PathBuf),
+}
+
+impl Log {
+    fn file(name: &str) -> Log {
+        let path = env::temp_dir().join(format!("action_kv-bench-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        Log::File(path)
+    }
+
+    fn both(name: &str) -> [Log; 2] {
+        [Log::Memory(MemoryStorage::new()), Log::file(name)]
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Log::Memory(_) => "memory",
+            Log::File(_) => "file",
+        }
+    }
+
+    fn open(&self) -> ActionKV {
+        let mut store = match self {
+            Log::Memory(storage) => {
+                ActionKV::with_storage(Box::new(storage.clone()), Options::default()).unwrap()
+            }
+            Log::File(path) => ActionKV::open(path).unwrap(),
+        };
+        store.load().unwrap();
+        store
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Log::Memory(storage) => storage.contents().len() as u64,
+            Log::File(path) => fs::metadata(path).unwrap().len(),
+        }
+    }
+
+    /// A copy of its own, for benchmarks that change the log
+    fn copy(&self, name: &str) -> Log {
+        match self {
+            Log::Memory(storage) => Log::Memory(MemoryStorage::from_bytes(storage.contents())),
+            Log::File(path) => {
+                let copy = Log::file(name);
+                if let Log::File(to) = &copy {
+                    fs::copy(path, to).unwrap();
+                }
+                copy
+            }
+        }
+    }
+}
+
+impl Drop for Log {
+    fn drop(&mut self) {
+        if let Log::File(path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Logs holding `KEYS` keys, every one of them written `versions` times
+fn populated(name: &str, versions: u32) -> [Log; 2] {
+    let logs = Log::both(name);
+    for log in &logs {
+        let mut store = log.open();
+        for _ in 0..versions {
+            for i in 0..KEYS {
+                store.insert(&key(i), VALUE).unwrap();
+            }
+        }
+    }
+    logs
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.throughput(Throughput::Elements(1));
+    for log in Log::both("insert") {
+        let mut store = log.open();
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("100 byte value", log.label()), |b| {
+            b.iter(|| {
+                store.insert(&key(i % KEYS), VALUE).unwrap();
+                i += 1;
+            })
+        });
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    group.throughput(Throughput::Elements(1));
+    for log in populated("get", 1) {
+        let mut store = log.open();
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("hit", log.label()), |b| {
+            b.iter(|| {
+                store.get(&key(i % KEYS)).unwrap().unwrap();
+                i += 1;
+            })
+        });
+        group.bench_function(BenchmarkId::new("miss", log.label()), |b| {
+            b.iter(|| {
+                assert!(store.get(&key(KEYS + i % KEYS)).unwrap().is_none());
+                i += 1;
+            })
+        });
+    }
+    group.finish();
+}
+
+fn load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("load");
+    group.sample_size(20);
+    for log in populated("load", 4) {
+        group.throughput(Throughput::Bytes(log.len()));
+        group.bench_function(BenchmarkId::new("4 versions per key", log.label()), |b| {
+            b.iter(|| log.open())
+        });
+    }
+    group.finish();
+}
+
+fn compact(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compact");
+    group.sample_size(20);
+    for log in populated("compact", 4) {
+        group.bench_function(BenchmarkId::new("4 versions per key", log.label()), |b| {
+            b.iter_batched(
+                || {
+                    let copy = log.copy("compact-copy");
+                    let store = copy.open();
+                    (copy, store)
+                },
+                // Handed back so that removing the copy is not timed
+                |(copy, mut store)| {
+                    store.compact().unwrap();
+                    (copy, store)
+                },
+                // One copy at a time, they share a file name
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert, get, load, compact);
+criterion_main!(benches);
@@ -0,0 +1,175 @@
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+use action_kv::dump::{self, Filter, Record};
+use clap::{App, Arg};
+use serde_json::json;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Printable UTF-8 as it is, anything else as hex
+fn text(bytes: &[u8], force_hex: bool) -> Option<String> {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !force_hex && !s.chars().any(char::is_control) => Some(s.to_string()),
+        _ => None,
+    }
+}
+
+fn show(bytes: &[u8], force_hex: bool) -> String {
+    match text(bytes, force_hex) {
+        Some(s) => format!("{:?}", s),
+        None => format!("0x{}", hex(bytes)),
+    }
+}
+
+fn offset(app: &clap::ArgMatches, name: &str, default: u64) -> u64 {
+    match app.value_of(name) {
+        None => default,
+        Some(raw) => raw.parse().unwrap_or_else(|_| {
+            eprintln!("--{}: {} is not an offset", name, raw);
+            process::exit(2);
+        }),
+    }
+}
+
+fn to_json(record: &Record, force_hex: bool) -> serde_json::Value {
+    json!({
+        "offset": record.offset,
+        "transaction": record.transaction,
+        "namespace": record.namespace,
+        "stored_checksum": record.stored_checksum,
+        "computed_checksum": record.computed_checksum,
+        "valid": record.is_valid(),
+        "key_len": record.key.len(),
+        "value_len": record.value.len(),
+        "key": text(&record.key, force_hex),
+        "key_hex": hex(&record.key),
+        "value": text(&record.value, force_hex),
+        "value_hex": hex(&record.value),
+    })
+}
+
+/// One line per record, the writes of a transaction indented under it
+fn describe(record: &Record, force_hex: bool) -> String {
+    let checksum = if record.is_valid() {
+        format!("{:08x}", record.stored_checksum)
+    } else {
+        format!(
+            "{:08x} != {:08x} BAD",
+            record.stored_checksum, record.computed_checksum
+        )
+    };
+    let indent = if record.transaction.is_some() {
+        "  "
+    } else {
+        ""
+    };
+    let kind = if record.is_transaction() {
+        "transaction".to_string()
+    } else if record.is_merge() {
+        "merge".to_string()
+    } else {
+        format!("ns {}", record.namespace)
+    };
+    format!(
+        "{}{:>10}  {}  {}  key {} ({})  value {} ({})",
+        indent,
+        record.offset,
+        kind,
+        checksum,
+        show(&record.key, force_hex),
+        record.key.len(),
+        show(&record.value, force_hex),
+        record.value.len(),
+    )
+}
+
+fn main() {
+    let app = App::new("akv_dump")
+        .about("Lists the records of an ActionKV log")
+        .arg(Arg::new("log").required(true))
+        .arg(
+            Arg::new("key")
+                .long("key")
+                .takes_value(true)
+                .value_name("PREFIX")
+                .help("Only records whose key starts with PREFIX"),
+        )
+        .arg(
+            Arg::new("from")
+                .long("from")
+                .takes_value(true)
+                .value_name("OFFSET")
+                .help("Only records starting at OFFSET or later"),
+        )
+        .arg(
+            Arg::new("to")
+                .long("to")
+                .takes_value(true)
+                .value_name("OFFSET")
+                .help("Only records starting before OFFSET"),
+        )
+        .arg(
+            Arg::new("hex")
+                .long("hex")
+                .help("Shows keys and values as hex even when they are UTF-8"),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Prints one JSON object per record"),
+        )
+        .get_matches();
+
+    let log = Path::new(app.value_of("log").unwrap());
+    let force_hex = app.is_present("hex");
+    let filter = Filter {
+        key_prefix: app.value_of("key").unwrap_or("").as_bytes().to_vec(),
+        offsets: offset(&app, "from", 0)..offset(&app, "to", u64::MAX),
+    };
+
+    let json = app.is_present("json");
+    let mut out = BufWriter::new(io::stdout().lock());
+    let written = dump::dump(log, &filter, |record| {
+        if json {
+            writeln!(out, "{}", to_json(&record, force_hex))
+        } else {
+            writeln!(out, "{}", describe(&record, force_hex))
+        }
+    })
+    .and_then(|dump| {
+        if json {
+            if let Some(offset) = dump.truncated_at {
+                writeln!(out, "{}", json!({ "truncated_at": offset }))?;
+            }
+        } else {
+            if let Some(offset) = dump.truncated_at {
+                writeln!(out, "{:>10}  record cut off by the end of the file", offset)?;
+            }
+            writeln!(
+                out,
+                "checksum {:?}, {} bytes, {} records shown",
+                dump.checksum, dump.file_size, dump.records
+            )?;
+        }
+        out.flush()?;
+        Ok(dump)
+    });
+
+    let dump = match written {
+        Ok(dump) => dump,
+        // Piped into something like head, which has seen enough
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => process::exit(0),
+        Err(e) => {
+            eprintln!("{}: {}", log.display(), e);
+            process::exit(2);
+        }
+    };
+
+    if dump.truncated_at.is_some() || dump.damaged > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::Path;
+use std::process;
+
+use action_kv::fsck::{self, Problem};
+use action_kv::ChecksumAlgorithm;
+use clap::{App, Arg};
+
+fn main() {
+    let app = App::new("akv_fsck")
+        .about("Verifies an ActionKV log and optionally writes a repaired copy")
+        .arg(Arg::new("log").required(true))
+        .arg(
+            Arg::new("repair")
+                .long("repair")
+                .takes_value(true)
+                .value_name("OUT")
+                .help("Writes every valid record to a new log at OUT"),
+        )
+        .arg(
+            Arg::new("assume-checksum")
+                .long("assume-checksum")
+                .takes_value(true)
+                .possible_values(["crc32", "crc32c", "xxhash32"])
+                .requires("repair")
+                .help(
+                    "Repairs a log whose file header is damaged, its records using this checksum",
+                ),
+        )
+        .get_matches();
+
+    let log = Path::new(app.value_of("log").unwrap());
+    let assume = app.value_of("assume-checksum").map(|name| match name {
+        "crc32" => ChecksumAlgorithm::Crc32,
+        "crc32c" => ChecksumAlgorithm::Crc32c,
+        _ => ChecksumAlgorithm::XxHash32,
+    });
+    let report = match (app.value_of("repair"), assume) {
+        (None, _) => fsck::verify(log),
+        (Some(out), None) => fsck::repair(log, Path::new(out)),
+        (Some(out), Some(checksum)) => fsck::repair_assuming(log, Path::new(out), checksum),
+    };
+
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}: {}", log.display(), e);
+            process::exit(2);
+        }
+    };
+
+    println!("checksum:       {:?}", report.checksum);
+    println!("file size:      {}", report.file_size);
+    println!("records:        {}", report.records);
+    println!("live records:   {}", report.live_records);
+    println!("dead records:   {}", report.dead_records);
+    println!("duplicate keys: {}", report.duplicate_keys);
+    println!("skipped bytes:  {}", report.skipped_bytes);
+
+    for problem in &report.problems {
+        match problem {
+            Problem::ChecksumMismatch { offset } => {
+                println!("{:>12}: checksum mismatch", offset)
+            }
+            Problem::PastEndOfFile { offset, record_len } => println!(
+                "{:>12}: record of {} bytes runs past the end of the file",
+                offset, record_len
+            ),
+            Problem::TruncatedHeader { offset } => {
+                println!("{:>12}: truncated record header", offset)
+            }
+            Problem::MalformedRecord { offset } => {
+                println!(
+                    "{:>12}: batch or merge operand that cannot be read back",
+                    offset
+                )
+            }
+            Problem::DamagedFileHeader => {
+                println!("{:>12}: damaged file header", 0)
+            }
+        }
+    }
+
+    if !report.is_clean() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,309 @@
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use action_kv::{metrics, ActionKV};
+use clap::{App, Arg};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// How many bytes a generated key or value gets
+#[derive(Debug, Clone, Copy)]
+enum Size {
+    Fixed(usize),
+    /// Anything from the first to the second, inclusive
+    Uniform(usize, usize),
+}
+
+impl Size {
+    fn sample(&self, rng: &mut StdRng) -> usize {
+        match *self {
+            Size::Fixed(len) => len,
+            Size::Uniform(min, max) => rng.gen_range(min..=max),
+        }
+    }
+}
+
+impl FromStr for Size {
+    type Err = String;
+
+    /// `N` for a fixed size, `MIN-MAX` for sizes spread evenly between the two
+    fn from_str(s: &str) -> Result<Size, String> {
+        let parse = |n: &str| {
+            n.parse::<usize>()
+                .map_err(|_| format!("{} is not a size", n))
+        };
+        let size = match s.split_once('-') {
+            None => Size::Fixed(parse(s)?),
+            Some((min, max)) => Size::Uniform(parse(min)?, parse(max)?),
+        };
+        match size {
+            Size::Fixed(0) | Size::Uniform(0, _) => Err("sizes start at 1".to_string()),
+            Size::Uniform(min, max) if min > max => Err(format!("{} is an empty range", s)),
+            size => Ok(size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Durability {
+    /// Leave it to the operating system
+    None,
+    /// Sync after every N writes
+    Batch(u64),
+    Always,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Durability, String> {
+        match s {
+            "none" => Ok(Durability::None),
+            "always" => Ok(Durability::Always),
+            _ => match s.strip_prefix("batch:").map(str::parse) {
+                Some(Ok(n)) if n > 0 => Ok(Durability::Batch(n)),
+                _ => Err(format!("{} is not none, always or batch:N", s)),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Get,
+    Insert,
+    Delete,
+}
+
+const OPS: [Op; 3] = [Op::Get, Op::Insert, Op::Delete];
+
+fn value_of<T: FromStr<Err = String>>(app: &clap::ArgMatches, name: &str) -> T {
+    let raw = app.value_of(name).unwrap();
+    raw.parse().unwrap_or_else(|e| {
+        eprintln!("--{}: {}", name, e);
+        process::exit(2);
+    })
+}
+
+fn number<T: FromStr>(app: &clap::ArgMatches, name: &str) -> T {
+    let raw = app.value_of(name).unwrap();
+    raw.parse().unwrap_or_else(|_| {
+        eprintln!("--{}: {} is not a number", name, raw);
+        process::exit(2);
+    })
+}
+
+fn key_for(id: u64, size: usize) -> Vec<u8> {
+    let mut key = format!("key:{}:", id).into_bytes();
+    key.resize(size.max(key.len()), b'.');
+    key
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+fn report(name: &str, latencies: &mut [Duration]) {
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort_unstable();
+    let micros = |d: Duration| d.as_secs_f64() * 1e6;
+    println!(
+        "{:<8} {:>10} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+        name,
+        latencies.len(),
+        micros(percentile(latencies, 0.50)),
+        micros(percentile(latencies, 0.90)),
+        micros(percentile(latencies, 0.99)),
+        micros(percentile(latencies, 0.999)),
+        micros(latencies[latencies.len() - 1]),
+    );
+}
+
+fn main() {
+    let app = App::new("akv_loadgen")
+        .about("Runs a mix of reads and writes against an ActionKV log and reports throughput and latency")
+        .arg(Arg::new("log").required(true))
+        .arg(
+            Arg::new("ops")
+                .long("ops")
+                .takes_value(true)
+                .default_value("100000")
+                .help("Operations to run"),
+        )
+        .arg(
+            Arg::new("keys")
+                .long("keys")
+                .takes_value(true)
+                .default_value("10000")
+                .help("Distinct keys the operations pick from"),
+        )
+        .arg(
+            Arg::new("key-size")
+                .long("key-size")
+                .takes_value(true)
+                .default_value("16")
+                .help("Key length in bytes, N or MIN-MAX"),
+        )
+        .arg(
+            Arg::new("value-size")
+                .long("value-size")
+                .takes_value(true)
+                .default_value("100")
+                .help("Value length in bytes, N or MIN-MAX"),
+        )
+        .arg(
+            Arg::new("reads")
+                .long("reads")
+                .takes_value(true)
+                .default_value("50")
+                .help("Percentage of operations that are reads"),
+        )
+        .arg(
+            Arg::new("deletes")
+                .long("deletes")
+                .takes_value(true)
+                .default_value("0")
+                .help("Percentage of operations that are deletes"),
+        )
+        .arg(
+            Arg::new("durability")
+                .long("durability")
+                .takes_value(true)
+                .default_value("none")
+                .help("When writes are synced: none, always or batch:N"),
+        )
+        .arg(
+            Arg::new("preload")
+                .long("preload")
+                .help("Writes every key once before measuring"),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("metrics")
+                .long("metrics")
+                .takes_value(true)
+                .value_name("ADDR")
+                .help("Serves Prometheus metrics at http://ADDR/metrics during the run"),
+        )
+        .get_matches();
+
+    let log = Path::new(app.value_of("log").unwrap());
+    let ops: u64 = number(&app, "ops");
+    let keys: u64 = number(&app, "keys");
+    let reads: u32 = number(&app, "reads");
+    let deletes: u32 = number(&app, "deletes");
+    let key_size: Size = value_of(&app, "key-size");
+    let value_size: Size = value_of(&app, "value-size");
+    let durability: Durability = value_of(&app, "durability");
+    let mut rng = StdRng::seed_from_u64(number(&app, "seed"));
+    if keys == 0 || reads + deletes > 100 {
+        eprintln!("need at least one key, and reads and deletes within 100%");
+        process::exit(2);
+    }
+
+    let mut store = ActionKV::open(log)
+        .and_then(|mut store| store.load().map(|_| store))
+        .unwrap_or_else(|e| {
+            eprintln!("{}: {}", log.display(), e);
+            process::exit(2);
+        });
+
+    if let Some(addr) = app.value_of("metrics") {
+        let listener = TcpListener::bind(addr).unwrap_or_else(|e| {
+            eprintln!("--metrics: {}: {}", addr, e);
+            process::exit(2);
+        });
+        let metrics = store.metrics();
+        thread::spawn(move || metrics::serve(listener, metrics));
+    }
+
+    // Keys keep one length for the whole run, so that every operation on a key hits the same one
+    let key_ids: Vec<Vec<u8>> = (0..keys)
+        .map(|id| key_for(id, key_size.sample(&mut rng)))
+        .collect();
+    let value = |rng: &mut StdRng| vec![b'v'; value_size.sample(rng)];
+
+    if app.is_present("preload") {
+        for key in &key_ids {
+            let val = value(&mut rng);
+            store.insert(key, &val).unwrap();
+        }
+        store.sync().unwrap();
+    }
+
+    let mut latencies: [Vec<Duration>; 3] = Default::default();
+    let mut writes: u64 = 0;
+    let started = Instant::now();
+    for _ in 0..ops {
+        let key = &key_ids[rng.gen_range(0..keys) as usize];
+        let roll = rng.gen_range(0..100);
+        let op = if roll < reads {
+            Op::Get
+        } else if roll < reads + deletes {
+            Op::Delete
+        } else {
+            Op::Insert
+        };
+        // Values are made before the clock starts, they are not part of the store's work
+        let val = match op {
+            Op::Insert => value(&mut rng),
+            _ => Vec::new(),
+        };
+
+        let start = Instant::now();
+        let result = match op {
+            Op::Get => store.get(key).map(|_| ()),
+            Op::Insert => store.insert(key, &val),
+            Op::Delete => store.delete(key),
+        };
+        let result = result.and_then(|_| {
+            if let Op::Get = op {
+                return Ok(());
+            }
+            writes += 1;
+            match durability {
+                Durability::Always => store.sync(),
+                Durability::Batch(n) if writes.is_multiple_of(n) => store.sync(),
+                _ => Ok(()),
+            }
+        });
+        latencies[op as usize].push(start.elapsed());
+
+        if let Err(e) = result {
+            eprintln!("{:?} failed: {}", op, e);
+            process::exit(1);
+        }
+    }
+    store.sync().unwrap();
+    let elapsed = started.elapsed();
+
+    println!(
+        "{} ops in {:.2}s, {:.0} ops/sec, durability {:?}",
+        ops,
+        elapsed.as_secs_f64(),
+        ops as f64 / elapsed.as_secs_f64(),
+        durability
+    );
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "op", "count", "p50 µs", "p90 µs", "p99 µs", "p99.9 µs", "max µs"
+    );
+    for op in OPS {
+        report(
+            &format!("{:?}", op).to_lowercase(),
+            &mut latencies[op as usize],
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! Bloom filters, one per segment. The log has one over its keys, so a lookup that it rules out
+//! never has to reach the index, and `load` rebuilds it from the index. Once the index is spilled
+//! every run on disk is a segment of its own, with a filter saved next to it in a hint file, so
+//! that a lookup skips the runs that cannot hold its key without reading them.
+
+use crate::{ActionKV, ByteStr, NamespaceId};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::f64::consts::LN_2;
+use std::io;
+use std::io::{Read, Write};
+use xxhash_rust::xxh32::xxh32;
+
+const MAGIC: &[u8; 4] = b"AKVB";
+/// Keys a filter of a fresh store is sized for, it grows from there
+const MIN_CAPACITY: u64 = 1024;
+pub(crate) const FALSE_POSITIVE_RATE: f64 = 0.01;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+    /// Keys the filter was sized for, past that the false positive rate climbs
+    capacity: u64,
+    len: u64,
+}
+
+impl BloomFilter {
+    /// Sized to answer with `false_positive_rate` once `capacity` keys are in
+    pub fn new(capacity: u64, false_positive_rate: f64) -> BloomFilter {
+        let capacity = capacity.max(1);
+        let bits = (-(capacity as f64) * false_positive_rate.ln() / (LN_2 * LN_2)).ceil();
+        let words = ((bits as u64).max(64)).div_ceil(64);
+        let hashes = ((words * 64) as f64 / capacity as f64 * LN_2).round();
+
+        BloomFilter {
+            bits: vec![0; words as usize],
+            hashes: (hashes as u32).clamp(1, 16),
+            capacity,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Double hashing, every probe is `h1 + i * h2` over the bit array
+    fn probes(&self, item: &ByteStr) -> impl Iterator<Item = usize> {
+        let h1 = xxh32(item, 0) as u64;
+        let h2 = xxh32(item, 0x9747_b28c) as u64 | 1;
+        let bits = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    pub fn insert(&mut self, item: &ByteStr) {
+        let probes: Vec<usize> = self.probes(item).collect();
+        for bit in probes {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// `false` means `item` was never inserted, `true` that it probably was
+    pub fn may_contain(&self, item: &ByteStr) -> bool {
+        self.probes(item)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Format is: magic(b"AKVB"), hashes(u32), capacity(u64), len(u64), words(u64), the bit
+    /// array as u64 words and a crc32 of everything before it
+    pub fn write_to<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(32 + self.bits.len() * 8);
+        buf.write_all(MAGIC)?;
+        buf.write_u32::<LittleEndian>(self.hashes)?;
+        buf.write_u64::<LittleEndian>(self.capacity)?;
+        buf.write_u64::<LittleEndian>(self.len)?;
+        buf.write_u64::<LittleEndian>(self.bits.len() as u64)?;
+        for word in &self.bits {
+            buf.write_u64::<LittleEndian>(*word)?;
+        }
+        let checksum = crc::crc32::checksum_ieee(&buf);
+        buf.write_u32::<LittleEndian>(checksum)?;
+        f.write_all(&buf)
+    }
+
+    pub fn read_from<R: Read>(f: &mut R) -> io::Result<BloomFilter> {
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf)?;
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        if buf.len() < 32 || &buf[..4] != MAGIC {
+            return Err(invalid("not a bloom filter"));
+        }
+        let (body, checksum) = buf.split_at(buf.len() - 4);
+        if crc::crc32::checksum_ieee(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(invalid("bloom filter checksum mismatch"));
+        }
+
+        let mut body = &body[4..];
+        let hashes = body.read_u32::<LittleEndian>()?;
+        let capacity = body.read_u64::<LittleEndian>()?;
+        let len = body.read_u64::<LittleEndian>()?;
+        let words = body.read_u64::<LittleEndian>()?;
+        if words == 0 || hashes == 0 || body.len() as u64 != words * 8 {
+            return Err(invalid("bloom filter size does not match its header"));
+        }
+
+        let mut bits = vec![0; words as usize];
+        body.read_u64_into::<LittleEndian>(&mut bits)?;
+        Ok(BloomFilter {
+            bits,
+            hashes,
+            capacity,
+            len,
+        })
+    }
+}
+
+fn filter_key(namespace: NamespaceId, key: &ByteStr) -> Vec<u8> {
+    let mut item = Vec::with_capacity(4 + key.len());
+    item.extend_from_slice(&namespace.to_le_bytes());
+    item.extend_from_slice(key);
+    item
+}
+
+impl ActionKV {
+    /// Filter over every key written since the last `load` or compaction
+    pub fn bloom_filter(&self) -> &BloomFilter {
+        &self.bloom
+    }
+
+    pub(crate) fn may_contain(&self, namespace: NamespaceId, key: &ByteStr) -> bool {
+        self.bloom.may_contain(&filter_key(namespace, key))
+    }
+
+    pub(crate) fn bloom_insert(&mut self, namespace: NamespaceId, key: &ByteStr) -> io::Result<()> {
+        if self.bloom.len() >= self.bloom.capacity() {
+            // Deleted keys are left behind too, they only ever leave the filter when it is rebuilt
+            self.rebuild_bloom_filter()?;
+        }
+        self.bloom.insert(&filter_key(namespace, key));
+        Ok(())
+    }
+
+    /// Sized for twice the live keys, so that it takes as many inserts again to fill it up
+    pub(crate) fn rebuild_bloom_filter(&mut self) -> io::Result<()> {
+        let live: u64 = self.index.values().map(|index| index.len() as u64).sum();
+        let mut bloom = BloomFilter::new((live * 2).max(MIN_CAPACITY), FALSE_POSITIVE_RATE);
+        for (namespace, index) in &self.index {
+            for entry in index.entries() {
+                let (key, _) = entry?;
+                bloom.insert(&filter_key(*namespace, &key));
+            }
+        }
+        self.bloom = bloom;
+        Ok(())
+    }
+}
+
+impl Default for BloomFilter {
+    fn default() -> BloomFilter {
+        BloomFilter::new(MIN_CAPACITY, FALSE_POSITIVE_RATE)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::BloomFilter;
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    pub fn test_bloom_filter() {
+        let mut bloom = BloomFilter::new(1000, 0.01);
+        for i in 0..1000u32 {
+            bloom.insert(&i.to_le_bytes());
+        }
+        assert!((0..1000u32).all(|i| bloom.may_contain(&i.to_le_bytes())));
+        let false_positives = (1000..11000u32)
+            .filter(|i| bloom.may_contain(&i.to_le_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        let mut bytes = Vec::new();
+        bloom.write_to(&mut bytes).unwrap();
+        assert_eq!(
+            BloomFilter::read_from(&mut bytes.as_slice()).unwrap(),
+            bloom
+        );
+        bytes[40] ^= 1;
+        assert!(BloomFilter::read_from(&mut bytes.as_slice()).is_err());
+        assert!(BloomFilter::read_from(&mut &bytes[..20]).is_err());
+    }
+
+    #[test]
+    pub fn test_store_filter_has_no_false_negatives() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        let users = akv.create_namespace("users").unwrap();
+        for i in 0..3000u32 {
+            akv.insert_in(users, &i.to_le_bytes(), b"x").unwrap();
+        }
+        assert!(akv.bloom_filter().capacity() > 3000);
+        assert!((0..3000u32).all(|i| akv.contains_key_in(users, &i.to_le_bytes()).unwrap()));
+
+        // Deleted keys are forgotten once the filter is rebuilt
+        akv.delete_in(users, &0u32.to_le_bytes()).unwrap();
+        akv.compact().unwrap();
+        assert_eq!(akv.bloom_filter().len(), 2999);
+
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.bloom_filter().len(), 2999);
+        let users = akv.namespace("users").unwrap();
+        assert_eq!(
+            akv.get_in(users, &7u32.to_le_bytes()).unwrap(),
+            Some(b"x".to_vec())
+        );
+        assert_eq!(akv.get_in(users, &0u32.to_le_bytes()).unwrap(), None);
+    }
+}
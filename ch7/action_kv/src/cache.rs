@@ -0,0 +1,231 @@
+use crate::{ActionKV, ByteStr, ByteString, NamespaceId};
+use std::collections::{BTreeMap, HashMap};
+
+/// Counters of the value cache, see `ActionKV::cache_stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Bytes the cache may hold, 0 when it is disabled
+    pub capacity: usize,
+    /// Bytes of keys and values currently cached
+    pub bytes: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: ByteString,
+    last_used: u64,
+}
+
+/// Values of recently used keys, evicting the least recently used ones once the cached keys
+/// and values take more than `capacity` bytes
+#[derive(Debug, Default)]
+pub(crate) struct ValueCache {
+    capacity: usize,
+    bytes: usize,
+    clock: u64,
+    entries: HashMap<(NamespaceId, ByteString), Entry>,
+    /// Keys by the time they were last used, the oldest first
+    recency: BTreeMap<u64, (NamespaceId, ByteString)>,
+    hits: u64,
+    misses: u64,
+}
+
+fn entry_size(key: &ByteStr, value: &ByteStr) -> usize {
+    key.len() + value.len()
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            ..ValueCache::default()
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    pub(crate) fn get(&mut self, namespace: NamespaceId, key: &ByteStr) -> Option<ByteString> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let now = self.tick();
+        let entry = match self.entries.get_mut(&(namespace, key.to_vec())) {
+            None => {
+                self.misses += 1;
+                return None;
+            }
+            Some(entry) => entry,
+        };
+
+        self.hits += 1;
+        let used = std::mem::replace(&mut entry.last_used, now);
+        let value = entry.value.clone();
+        if let Some(cache_key) = self.recency.remove(&used) {
+            self.recency.insert(now, cache_key);
+        }
+        Some(value)
+    }
+
+    pub(crate) fn insert(&mut self, namespace: NamespaceId, key: &ByteStr, value: &ByteStr) {
+        self.remove(namespace, key);
+
+        let size = entry_size(key, value);
+        if size > self.capacity {
+            return;
+        }
+
+        let now = self.tick();
+        self.bytes += size;
+        self.entries.insert(
+            (namespace, key.to_vec()),
+            Entry {
+                value: value.to_vec(),
+                last_used: now,
+            },
+        );
+        self.recency.insert(now, (namespace, key.to_vec()));
+        self.evict();
+    }
+
+    pub(crate) fn remove(&mut self, namespace: NamespaceId, key: &ByteStr) {
+        let cache_key = (namespace, key.to_vec());
+        if let Some(entry) = self.entries.remove(&cache_key) {
+            self.bytes -= entry_size(key, &entry.value);
+            self.recency.remove(&entry.last_used);
+        }
+    }
+
+    pub(crate) fn remove_namespace(&mut self, namespace: NamespaceId) {
+        let keys: Vec<ByteString> = self
+            .entries
+            .keys()
+            .filter(|(ns, _)| *ns == namespace)
+            .map(|(_, key)| key.clone())
+            .collect();
+
+        for key in keys {
+            self.remove(namespace, &key);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.bytes = 0;
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.bytes > self.capacity {
+            let (_, (namespace, key)) = match self.recency.pop_first() {
+                None => break,
+                Some(oldest) => oldest,
+            };
+            if let Some(entry) = self.entries.remove(&(namespace, key.clone())) {
+                self.bytes -= entry_size(&key, &entry.value);
+            }
+        }
+    }
+}
+
+impl ActionKV {
+    /// Resizes the value cache, 0 disables it. Shrinking evicts the least recently used values
+    pub fn set_value_cache(&mut self, capacity: usize) {
+        self.cache.set_capacity(capacity);
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            capacity: self.cache.capacity,
+            bytes: self.cache.bytes,
+            entries: self.cache.entries.len(),
+            hits: self.cache.hits,
+            misses: self.cache.misses,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::CacheStats;
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+    use crate::{ActionKV, Options};
+
+    #[test]
+    pub fn test_cache_hits_and_eviction() {
+        let options = Options {
+            value_cache: 12,
+            ..Options::default()
+        };
+        let mut akv = ActionKV::with_storage(Box::new(MemoryStorage::new()), options).unwrap();
+        akv.insert(b"a", b"11111").unwrap();
+        akv.insert(b"b", b"22222").unwrap();
+
+        assert_eq!(akv.get(b"a").unwrap(), Some(b"11111".to_vec()));
+        assert_eq!(akv.get(b"b").unwrap(), Some(b"22222".to_vec()));
+        // "a" was used last, so "b" makes room for "c"
+        akv.get(b"a").unwrap();
+        akv.insert(b"c", b"33333").unwrap();
+        assert_eq!(akv.get(b"b").unwrap(), Some(b"22222".to_vec()));
+        assert_eq!(
+            akv.cache_stats(),
+            CacheStats {
+                capacity: 12,
+                bytes: 12,
+                entries: 2,
+                hits: 3,
+                misses: 1,
+            }
+        );
+
+        // Too big to ever fit, served from the log every time
+        akv.insert(b"big", b"0123456789").unwrap();
+        assert_eq!(akv.get(b"big").unwrap(), Some(b"0123456789".to_vec()));
+        assert_eq!(akv.cache_stats().misses, 2);
+
+        akv.set_value_cache(6);
+        assert_eq!(akv.cache_stats().entries, 1);
+        akv.set_value_cache(0);
+        assert_eq!(akv.cache_stats().bytes, 0);
+        akv.get(b"a").unwrap();
+        assert_eq!(akv.cache_stats().misses, 2);
+    }
+
+    #[test]
+    pub fn test_cache_invalidation() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        akv.set_value_cache(1024);
+        let users = akv.create_namespace("users").unwrap();
+        akv.insert(b"a", b"1").unwrap();
+        akv.insert_in(users, b"a", b"2").unwrap();
+        akv.insert(b"a", b"3").unwrap();
+        assert_eq!(akv.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(akv.get_in(users, b"a").unwrap(), Some(b"2".to_vec()));
+
+        akv.delete(b"a").unwrap();
+        assert_eq!(akv.get(b"a").unwrap(), None);
+        assert_eq!(akv.cache_stats().entries, 1);
+
+        akv.compact().unwrap();
+        assert_eq!(akv.cache_stats().entries, 0);
+        assert_eq!(akv.get_in(users, b"a").unwrap(), Some(b"2".to_vec()));
+
+        akv.drop_namespace("users").unwrap();
+        assert_eq!(akv.cache_stats().bytes, 0);
+        let users = akv.create_namespace("users").unwrap();
+        assert_eq!(akv.get_in(users, b"a").unwrap(), None);
+    }
+}
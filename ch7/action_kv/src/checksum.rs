@@ -0,0 +1,169 @@
+use crate::{ByteStr, NamespaceId};
+use crc::crc32::{self, Hasher32};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use xxhash_rust::xxh32::Xxh32;
+
+/// Checksum protecting every record of a log. The choice is made when the log is created and
+/// recorded in its file header, so a log is always verified with the algorithm that wrote it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumAlgorithm {
+    /// CRC-32 with the IEEE polynomial, what ActionKV has always used
+    #[default]
+    Crc32,
+    /// CRC-32 with the Castagnoli polynomial, computed with the SSE 4.2 and ARMv8 CRC
+    /// instructions where the CPU has them
+    Crc32c,
+    /// 32 bit xxHash, the fastest in software
+    XxHash32,
+}
+
+impl ChecksumAlgorithm {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            ChecksumAlgorithm::Crc32 => 1,
+            ChecksumAlgorithm::Crc32c => 2,
+            ChecksumAlgorithm::XxHash32 => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> io::Result<ChecksumAlgorithm> {
+        match id {
+            1 => Ok(ChecksumAlgorithm::Crc32),
+            2 => Ok(ChecksumAlgorithm::Crc32c),
+            3 => Ok(ChecksumAlgorithm::XxHash32),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown checksum algorithm {}", id),
+            )),
+        }
+    }
+
+    /// Checksum of a record, covering its namespace, key and value
+    pub fn checksum(self, namespace: NamespaceId, data: &ByteStr) -> u32 {
+        let namespace = namespace.to_le_bytes();
+
+        match self {
+            ChecksumAlgorithm::Crc32 => {
+                let mut digest = crc32::Digest::new(crc32::IEEE);
+                digest.write(&namespace);
+                digest.write(data);
+                digest.sum32()
+            }
+            ChecksumAlgorithm::Crc32c => crc32c::crc32c_append(crc32c::crc32c(&namespace), data),
+            ChecksumAlgorithm::XxHash32 => {
+                let mut digest = Xxh32::new(0);
+                digest.update(&namespace);
+                digest.update(data);
+                digest.digest()
+            }
+        }
+    }
+}
+
+/// Carried inside the `io::Error` returned when a record does not match its checksum
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub stored: u32,
+    pub computed: u32,
+}
+
+impl ChecksumMismatch {
+    pub fn from_io(e: &io::Error) -> Option<&ChecksumMismatch> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Data corruption encountered({:08x} != {:08x})",
+            self.computed, self.stored
+        )
+    }
+}
+
+impl Error for ChecksumMismatch {}
+
+#[cfg(test)]
+pub mod tests {
+    use super::ChecksumAlgorithm;
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+    use crate::{ActionKV, Options, DEFAULT_NAMESPACE};
+    use crc::crc32::{self, Hasher32};
+
+    #[test]
+    pub fn test_known_values() {
+        // Published check values for "123456789", whose first 4 bytes are the namespace
+        let namespace = u32::from_le_bytes(*b"1234");
+        let check = |algorithm: ChecksumAlgorithm| algorithm.checksum(namespace, b"56789");
+        assert_eq!(check(ChecksumAlgorithm::Crc32), 0xcbf4_3926);
+        assert_eq!(check(ChecksumAlgorithm::Crc32c), 0xe306_9283);
+        assert_eq!(check(ChecksumAlgorithm::XxHash32), 0x937b_ad67);
+
+        let all = [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Crc32c,
+            ChecksumAlgorithm::XxHash32,
+        ];
+        for algorithm in all {
+            assert_eq!(
+                ChecksumAlgorithm::from_id(algorithm.id()).unwrap(),
+                algorithm
+            );
+            assert_ne!(
+                algorithm.checksum(DEFAULT_NAMESPACE, b"vlad"),
+                algorithm.checksum(1, b"vlad")
+            );
+        }
+        assert!(ChecksumAlgorithm::from_id(0).is_err());
+    }
+
+    #[test]
+    pub fn test_crc32c_vectors() {
+        // The namespace makes up the first 4 bytes of what is checksummed
+        let crc32c = |data: &[u8]| {
+            let namespace = u32::from_le_bytes(data[..4].try_into().unwrap());
+            ChecksumAlgorithm::Crc32c.checksum(namespace, &data[4..])
+        };
+        let incrementing: Vec<u8> = (0..32).collect();
+        let decrementing: Vec<u8> = (0..32).rev().collect();
+
+        // From RFC 3720, appendix B.4
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a91_36aa);
+        assert_eq!(crc32c(&[0xff; 32]), 0x62a8_ab43);
+        assert_eq!(crc32c(&incrementing), 0x46dd_794e);
+        assert_eq!(crc32c(&decrementing), 0x113f_db5c);
+
+        // Logs written with the software implementation still check out
+        for len in [0, 1, 7, 8, 63, 64, 1000] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+            let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+            digest.write(&7u32.to_le_bytes());
+            digest.write(&data);
+            assert_eq!(ChecksumAlgorithm::Crc32c.checksum(7, &data), digest.sum32());
+        }
+    }
+
+    #[test]
+    pub fn test_algorithm_recorded_in_file() {
+        let storage = MemoryStorage::new();
+        let options = Options {
+            checksum: ChecksumAlgorithm::XxHash32,
+            ..Options::default()
+        };
+        let mut akv = ActionKV::with_storage(Box::new(storage.clone()), options).unwrap();
+        akv.insert(b"vlad", b"onis").unwrap();
+        akv.compact().unwrap();
+        assert_eq!(akv.checksum_algorithm(), ChecksumAlgorithm::XxHash32);
+
+        // The file header wins over the options of whoever opens it later
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.checksum_algorithm(), ChecksumAlgorithm::XxHash32);
+        assert_eq!(akv.get(b"vlad").unwrap(), Some(b"onis".to_vec()));
+    }
+}
@@ -0,0 +1,63 @@
+use crate::{ByteStr, ByteString};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+
+/// Turns serde types into the raw bytes stored by `ActionKV` and back.
+/// Encoding must be deterministic for keys, otherwise the same key could be stored twice
+pub trait Codec {
+    fn encode<T: Serialize>(value: &T) -> io::Result<ByteString>;
+    fn decode<T: DeserializeOwned>(bytes: &ByteStr) -> io::Result<T>;
+}
+
+/// Compact binary encoding, the default for `TypedStore`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+/// Human readable encoding, handy when the log is inspected by hand
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(value: &T) -> io::Result<ByteString> {
+        bincode::serialize(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &ByteStr) -> io::Result<T> {
+        bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Codec for Json {
+    fn encode<T: Serialize>(value: &T) -> io::Result<ByteString> {
+        serde_json::to_vec(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &ByteStr) -> io::Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Bincode, Codec, Json};
+    use std::io;
+
+    #[test]
+    pub fn test_round_trip() {
+        let value = (42u32, String::from("vlad"));
+
+        let bytes = Bincode::encode(&value).unwrap();
+        assert_eq!(Bincode::decode::<(u32, String)>(&bytes).unwrap(), value);
+
+        let bytes = Json::encode(&value).unwrap();
+        assert_eq!(bytes, br#"[42,"vlad"]"#);
+        assert_eq!(Json::decode::<(u32, String)>(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    pub fn test_decode_error() {
+        let err = Json::decode::<u32>(b"not json").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,285 @@
+//! Record by record listing of a log, for reading the format without doing it by hand. Unlike
+//! `load`, a checksum mismatch does not stop the walk, only a record cut off by the end does.
+
+use crate::merge::MERGE_NAMESPACE;
+use crate::transaction::TRANSACTION_NAMESPACE;
+use crate::{
+    record_len, ActionKV, ByteStr, ByteString, ChecksumAlgorithm, NamespaceId, FILE_HEADER_LEN,
+    HEADER_LEN,
+};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read};
+use std::ops::Range;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub offset: u64,
+    /// Offset of the transaction record this write is part of
+    pub transaction: Option<u64>,
+    pub namespace: NamespaceId,
+    pub stored_checksum: u32,
+    pub computed_checksum: u32,
+    pub key: ByteString,
+    pub value: ByteString,
+}
+
+impl Record {
+    pub fn is_valid(&self) -> bool {
+        self.stored_checksum == self.computed_checksum
+    }
+
+    /// Holds the writes of a committed transaction, which follow it in the dump
+    pub fn is_transaction(&self) -> bool {
+        self.namespace == TRANSACTION_NAMESPACE
+    }
+
+    /// Holds a merge operand. Its value starts with the namespace it merges into and the offset
+    /// of the previous record of the key
+    pub fn is_merge(&self) -> bool {
+        self.namespace == MERGE_NAMESPACE
+    }
+
+    /// Bytes the record takes up in the log, header included
+    pub fn size(&self) -> u64 {
+        record_len(self.key.len(), self.value.len())
+    }
+}
+
+/// Which records `dump` hands on, the default lets everything through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    /// Only records whose key starts with this
+    pub key_prefix: ByteString,
+    /// Only records starting within this range of offsets
+    pub offsets: Range<u64>,
+}
+
+impl Filter {
+    fn matches(&self, record: &Record) -> bool {
+        self.offsets.contains(&record.offset) && record.key.starts_with(&self.key_prefix)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter {
+            key_prefix: ByteString::new(),
+            offsets: 0..u64::MAX,
+        }
+    }
+}
+
+/// What `dump` found, besides the records themselves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dump {
+    pub checksum: ChecksumAlgorithm,
+    pub file_size: u64,
+    /// Records the filter let through
+    pub records: u64,
+    /// Those of them that do not match their checksum
+    pub damaged: u64,
+    /// Offset of a last record that the end of the file cuts off
+    pub truncated_at: Option<u64>,
+}
+
+/// Walks the log at `path` and hands the records `filter` lets through to `visit` one at a
+/// time, so the log never has to fit in memory. The writes of a transaction come right after
+/// the record holding them. An error from `visit` stops the walk
+pub fn dump<F>(path: &Path, filter: &Filter, mut visit: F) -> io::Result<Dump>
+where
+    F: FnMut(Record) -> io::Result<()>,
+{
+    let mut f = BufReader::new(File::open(path)?);
+    let file_size = f.get_ref().metadata()?.len();
+    let checksum = ActionKV::read_file_header(&mut f)?;
+
+    let mut dump = Dump {
+        checksum,
+        file_size,
+        records: 0,
+        damaged: 0,
+        truncated_at: None,
+    };
+    let mut shown = |record: Record| {
+        if !filter.matches(&record) {
+            return Ok(());
+        }
+        dump.records += 1;
+        if !record.is_valid() {
+            dump.damaged += 1;
+        }
+        visit(record)
+    };
+
+    let mut offset = FILE_HEADER_LEN;
+    let mut truncated_at = None;
+    while offset < file_size.min(filter.offsets.end) {
+        let record = match read_record(&mut f, offset, file_size - offset, checksum)? {
+            Some(record) => record,
+            None => {
+                truncated_at = Some(offset);
+                break;
+            }
+        };
+        offset += record.size();
+
+        // A damaged transaction is shown as it is, its value cannot be trusted to hold records
+        let writes = if record.is_transaction() && record.is_valid() {
+            split(&record, checksum)?
+        } else {
+            Vec::new()
+        };
+        shown(record)?;
+        for write in writes {
+            shown(write)?;
+        }
+    }
+
+    dump.truncated_at = truncated_at;
+    Ok(dump)
+}
+
+/// `None` when the record needs more than the `remaining` bytes
+fn read_record<R: Read>(
+    f: &mut R,
+    offset: u64,
+    remaining: u64,
+    algorithm: ChecksumAlgorithm,
+) -> io::Result<Option<Record>> {
+    if remaining < HEADER_LEN {
+        return Ok(None);
+    }
+    let stored_checksum = f.read_u32::<LittleEndian>()?;
+    let namespace = f.read_u32::<LittleEndian>()?;
+    let key_len = f.read_u32::<LittleEndian>()? as usize;
+    let val_len = f.read_u32::<LittleEndian>()? as usize;
+    if record_len(key_len, val_len) > remaining {
+        return Ok(None);
+    }
+
+    let mut data = vec![0; key_len + val_len];
+    f.read_exact(&mut data)?;
+    let computed_checksum = algorithm.checksum(namespace, &data);
+    let value = data.split_off(key_len);
+
+    Ok(Some(Record {
+        offset,
+        transaction: None,
+        namespace,
+        stored_checksum,
+        computed_checksum,
+        key: data,
+        value,
+    }))
+}
+
+fn split(batch: &Record, algorithm: ChecksumAlgorithm) -> io::Result<Vec<Record>> {
+    let mut offset = batch.offset + HEADER_LEN + batch.key.len() as u64;
+    let mut data: &ByteStr = &batch.value;
+    let mut writes = Vec::new();
+
+    while !data.is_empty() {
+        let remaining = data.len() as u64;
+        let mut write = match read_record(&mut data, offset, remaining, algorithm)? {
+            Some(write) => write,
+            None => break,
+        };
+        write.transaction = Some(batch.offset);
+        offset += write.size();
+        writes.push(write);
+    }
+
+    Ok(writes)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{dump, Dump, Filter, Record};
+    use crate::storage::tests::temp_dir;
+    use crate::transaction::TRANSACTION_NAMESPACE;
+    use crate::{record_len, ActionKV, FILE_HEADER_LEN, HEADER_LEN};
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    fn collect(path: &Path, filter: &Filter) -> (Dump, Vec<Record>) {
+        let mut records = Vec::new();
+        let dump = dump(path, filter, |record| {
+            records.push(record);
+            Ok(())
+        })
+        .unwrap();
+        (dump, records)
+    }
+
+    #[test]
+    pub fn test_dump_records() {
+        let dir = temp_dir();
+        let path = &dir.join("log");
+
+        let mut akv = ActionKV::open(path).unwrap();
+        akv.insert(b"apple", b"red").unwrap();
+        akv.insert(b"banana", b"yellow").unwrap();
+        let mut tx = akv.begin();
+        tx.insert(b"apricot", b"orange");
+        tx.delete(b"banana");
+        akv.commit(tx).unwrap();
+        drop(akv);
+
+        let (summary, all) = collect(path, &Filter::default());
+        let keys: Vec<&[u8]> = all.iter().map(|r| r.key.as_slice()).collect();
+        assert_eq!(
+            keys,
+            vec![&b"apple"[..], b"banana", b"", b"apricot", b"banana"]
+        );
+        assert!(all.iter().all(|r| r.is_valid()));
+        assert_eq!(all[2].namespace, TRANSACTION_NAMESPACE);
+        let batch = all[2].offset;
+        assert_eq!(all[3].transaction, Some(batch));
+        assert_eq!(all[3].offset, batch + HEADER_LEN);
+        assert!(all[4].value.is_empty());
+        assert_eq!((summary.records, summary.damaged), (5, 0));
+        assert_eq!(summary.truncated_at, None);
+
+        let filter = Filter {
+            key_prefix: b"ap".to_vec(),
+            offsets: FILE_HEADER_LEN + 1..u64::MAX,
+        };
+        let (summary, apricot) = collect(path, &filter);
+        assert_eq!(apricot, vec![all[3].clone()]);
+        assert_eq!(summary.records, 1);
+
+        // A damaged record is still listed, a cut off one ends the walk
+        let mut f = OpenOptions::new().write(true).open(path).unwrap();
+        f.seek(SeekFrom::Start(FILE_HEADER_LEN + record_len(5, 3) - 1))
+            .unwrap();
+        f.write_all(b"R").unwrap();
+        f.seek(SeekFrom::End(0)).unwrap();
+        f.write_all(&[1, 2, 3]).unwrap();
+        drop(f);
+
+        let (summary, damaged) = collect(path, &Filter::default());
+        assert_eq!(damaged.len(), 5);
+        assert!(!damaged[0].is_valid());
+        assert_eq!(damaged[0].value, b"reR".to_vec());
+        assert_eq!(summary.damaged, 1);
+        assert_eq!(summary.truncated_at, Some(summary.file_size - 3));
+
+        // Stopped by the first error of the visitor
+        let mut seen = 0;
+        let err = dump(path, &Filter::default(), |_| {
+            seen += 1;
+            Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "reader went away",
+            ))
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(seen, 1);
+    }
+}
@@ -0,0 +1,611 @@
+//! Offline verification and repair of ActionKV logs. Nothing here needs the log to be loadable,
+//! a damaged region is skipped by searching for the next offset holding a valid record. A damaged
+//! transaction batch is skipped as a whole, so that a repair never keeps part of a transaction.
+
+use crate::merge::{Operand, MERGE_NAMESPACE};
+use crate::namespace::CATALOG_NAMESPACE;
+use crate::transaction::{split_batch, TRANSACTION_NAMESPACE};
+use crate::{
+    record_len, ActionKV, ByteString, ChecksumAlgorithm, KeyValuePair, Limits, NamespaceId,
+    DEFAULT_NAMESPACE, FILE_HEADER_LEN, HEADER_LEN,
+};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Bytes `resync` reads ahead of the offset it is at
+const RESYNC_WINDOW: u64 = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The record is complete but its checksum does not match its contents
+    ChecksumMismatch { offset: u64 },
+    /// The length fields claim more bytes than are left in the file
+    PastEndOfFile { offset: u64, record_len: u64 },
+    /// Fewer bytes than a record header are left in the file
+    TruncatedHeader { offset: u64 },
+    /// The record passes its checksum, but the writes of its transaction batch or its merge
+    /// operand cannot be read back
+    MalformedRecord { offset: u64 },
+    /// The file header does not name the checksum algorithm the repair was told to assume
+    DamagedFileHeader,
+}
+
+impl Problem {
+    pub fn offset(&self) -> u64 {
+        match self {
+            Problem::ChecksumMismatch { offset }
+            | Problem::PastEndOfFile { offset, .. }
+            | Problem::TruncatedHeader { offset }
+            | Problem::MalformedRecord { offset } => *offset,
+            Problem::DamagedFileHeader => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub checksum: ChecksumAlgorithm,
+    pub file_size: u64,
+    /// Records that passed their checksum, every write of a transaction counts as one
+    pub records: u64,
+    /// Records a `load` would keep in the index
+    pub live_records: u64,
+    /// Stale versions, tombstones and records of dropped namespaces
+    pub dead_records: u64,
+    /// Keys written more than once
+    pub duplicate_keys: u64,
+    /// One entry per damaged region, found at its first bad offset
+    pub problems: Vec<Problem>,
+    /// Bytes skipped while searching for the next valid record
+    pub skipped_bytes: u64,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks every record of the log at `path` without changing it
+pub fn verify(path: &Path) -> io::Result<Report> {
+    scan(path, None, None)
+}
+
+/// Like `verify`, and writes every valid record to a new log at `out`, leaving the damaged
+/// regions behind
+pub fn repair(path: &Path, out: &Path) -> io::Result<Report> {
+    if path == out {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the repaired copy must not overwrite the original log",
+        ));
+    }
+    scan(path, Some(out), None)
+}
+
+/// Like `repair`, for a log whose file header is damaged. Its records are taken to be
+/// checksummed with `checksum` whatever the header says
+pub fn repair_assuming(path: &Path, out: &Path, checksum: ChecksumAlgorithm) -> io::Result<Report> {
+    if path == out {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the repaired copy must not overwrite the original log",
+        ));
+    }
+    scan(path, Some(out), Some(checksum))
+}
+
+#[derive(Debug, Default)]
+struct Versions {
+    count: u64,
+    /// Records the current value is made of, the last full value and the operands merged onto it
+    live: u64,
+    catalog_id: Option<NamespaceId>,
+}
+
+fn scan(path: &Path, out: Option<&Path>, assume: Option<ChecksumAlgorithm>) -> io::Result<Report> {
+    let mut f = BufReader::new(File::open(path)?);
+    let file_size = f.get_ref().metadata()?.len();
+    let mut problems = Vec::new();
+    let checksum = match assume {
+        None => ActionKV::read_file_header(&mut f)?,
+        Some(checksum) => {
+            if ActionKV::read_file_header(&mut f).ok() != Some(checksum) {
+                problems.push(Problem::DamagedFileHeader);
+            }
+            checksum
+        }
+    };
+
+    let mut out = match out {
+        None => None,
+        Some(out) => {
+            let out = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(out)?;
+            let mut out = BufWriter::new(out);
+            ActionKV::write_file_header(&mut out, checksum)?;
+            Some(out)
+        }
+    };
+
+    let mut report = Report {
+        checksum,
+        file_size,
+        records: 0,
+        live_records: 0,
+        dead_records: 0,
+        duplicate_keys: 0,
+        problems,
+        skipped_bytes: 0,
+    };
+    let mut versions: HashMap<(NamespaceId, ByteString), Versions> = HashMap::new();
+
+    let mut offset = FILE_HEADER_LEN;
+    while offset < file_size {
+        let kv = match read_at(&mut f, offset, file_size, checksum)? {
+            Ok(kv) => kv,
+            Err(problem) => {
+                report.problems.push(problem);
+                let next = match batch_end(&mut f, offset, file_size)? {
+                    // The writes inside a damaged batch may pass their own checksums, but
+                    // keeping some of them would break up the transaction
+                    Some(end) => end,
+                    None => resync(&mut f, offset + 1, file_size, checksum)?,
+                };
+                report.skipped_bytes += next - offset;
+                offset = next;
+                continue;
+            }
+        };
+
+        let position = offset;
+        let len = record_len(kv.key.len(), kv.value.len());
+        offset += len;
+        let records = match decode(position, &kv, checksum) {
+            Ok(records) => records,
+            Err(_) => {
+                // Dropped as a whole, like a damaged batch
+                report
+                    .problems
+                    .push(Problem::MalformedRecord { offset: position });
+                report.skipped_bytes += len;
+                continue;
+            }
+        };
+        if let Some(out) = out.as_mut() {
+            ActionKV::write_record(out, checksum, kv.namespace, &kv.key, &kv.value)?;
+        }
+
+        for (namespace, kv) in records {
+            report.records += 1;
+            if kv.namespace == MERGE_NAMESPACE {
+                let entry = versions.entry((namespace, kv.key)).or_default();
+                entry.count += 1;
+                entry.live += 1;
+                continue;
+            }
+
+            let entry = versions.entry((namespace, kv.key)).or_default();
+            entry.count += 1;
+            entry.live = !kv.value.is_empty() as u64;
+            if kv.namespace == CATALOG_NAMESPACE {
+                entry.catalog_id = kv.value.as_slice().try_into().ok().map(u32::from_le_bytes);
+            }
+        }
+    }
+
+    if let Some(out) = out {
+        let out = out.into_inner().map_err(|e| e.into_error())?;
+        out.sync_all()?;
+    }
+
+    let mut live_namespaces: HashSet<NamespaceId> = versions
+        .iter()
+        .filter(|(_, versions)| versions.live > 0)
+        .filter_map(|(_, versions)| versions.catalog_id)
+        .collect();
+    live_namespaces.insert(DEFAULT_NAMESPACE);
+    live_namespaces.insert(CATALOG_NAMESPACE);
+
+    for ((namespace, _), versions) in &versions {
+        if versions.count > 1 {
+            report.duplicate_keys += 1;
+        }
+        if live_namespaces.contains(namespace) {
+            report.live_records += versions.live;
+        }
+    }
+    report.dead_records = report.records - report.live_records;
+
+    Ok(report)
+}
+
+/// The records `kv` stands for, every write of a transaction counting as one, each with the
+/// namespace it belongs to. An error when the batch or an operand cannot be read back
+fn decode(
+    position: u64,
+    kv: &KeyValuePair,
+    checksum: ChecksumAlgorithm,
+) -> io::Result<Vec<(NamespaceId, KeyValuePair)>> {
+    let records = if kv.namespace == TRANSACTION_NAMESPACE {
+        split_batch(position, kv.clone(), checksum, Limits::MAX)?
+    } else {
+        vec![(position, kv.clone())]
+    };
+    records
+        .into_iter()
+        .map(|(_, kv)| {
+            // An operand counts as a version of the key it merges into
+            let namespace = if kv.namespace == MERGE_NAMESPACE {
+                Operand::decode(&kv.value)?.namespace
+            } else {
+                kv.namespace
+            };
+            Ok((namespace, kv))
+        })
+        .collect()
+}
+
+/// The outer error is an I/O failure, the inner one a damaged record
+fn read_at(
+    f: &mut BufReader<File>,
+    offset: u64,
+    file_size: u64,
+    checksum: ChecksumAlgorithm,
+) -> io::Result<Result<KeyValuePair, Problem>> {
+    if file_size - offset < HEADER_LEN {
+        return Ok(Err(Problem::TruncatedHeader { offset }));
+    }
+
+    // Look at the lengths first so that garbage never turns into a huge allocation
+    f.seek(SeekFrom::Start(offset + HEADER_LEN - 8))?;
+    let key_len = f.read_u32::<LittleEndian>()?;
+    let val_len = f.read_u32::<LittleEndian>()?;
+    let len = record_len(key_len as usize, val_len as usize);
+    if len > file_size - offset {
+        return Ok(Err(Problem::PastEndOfFile {
+            offset,
+            record_len: len,
+        }));
+    }
+
+    f.seek(SeekFrom::Start(offset))?;
+    // The lengths were already checked against the file size, whatever limits the log was
+    // written with are unknown here
+    match ActionKV::process_record(f, checksum, Limits::MAX) {
+        Ok(kv) => Ok(Ok(kv)),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            Ok(Err(Problem::ChecksumMismatch { offset }))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Where the record at `offset` ends when its header says it is the batch of a transaction,
+/// cut at the end of the file
+fn batch_end(f: &mut BufReader<File>, offset: u64, file_size: u64) -> io::Result<Option<u64>> {
+    if file_size - offset < HEADER_LEN {
+        return Ok(None);
+    }
+    f.seek(SeekFrom::Start(offset + 4))?;
+    let namespace = f.read_u32::<LittleEndian>()?;
+    let key_len = f.read_u32::<LittleEndian>()?;
+    let val_len = f.read_u32::<LittleEndian>()?;
+    if namespace != TRANSACTION_NAMESPACE {
+        return Ok(None);
+    }
+    let len = record_len(key_len as usize, val_len as usize);
+    Ok(Some(file_size.min(offset + len)))
+}
+
+/// First offset from `start` on that holds a valid record, or the end of the file. Offsets are
+/// tried within a window read ahead of them, a record that fits is checked right there. A longer
+/// one is only read when its lengths fit in the file and so do those of the record after it
+fn resync(
+    f: &mut BufReader<File>,
+    start: u64,
+    file_size: u64,
+    checksum: ChecksumAlgorithm,
+) -> io::Result<u64> {
+    let mut window = Vec::new();
+    let mut window_start = start;
+    for candidate in start..file_size {
+        let mut at = (candidate - window_start) as usize;
+        if ((window.len() - at) as u64) < RESYNC_WINDOW.min(file_size - candidate) {
+            window.drain(..at);
+            window_start = candidate;
+            at = 0;
+            let kept = window.len();
+            let end = file_size.min(candidate + 2 * RESYNC_WINDOW);
+            window.resize((end - candidate) as usize, 0);
+            f.seek(SeekFrom::Start(candidate + kept as u64))?;
+            f.read_exact(&mut window[kept..])?;
+        }
+
+        let rest = &window[at..];
+        if rest.len() < HEADER_LEN as usize {
+            // Only a truncated header is left, at this offset and every one after it
+            break;
+        }
+        let len = header_record_len(rest);
+        if len > file_size - candidate {
+            continue;
+        }
+        if len <= rest.len() as u64 {
+            if record_matches(&rest[..len as usize], checksum) {
+                return Ok(candidate);
+            }
+        } else if next_header_fits(f, candidate + len, file_size)?
+            && read_at(f, candidate, file_size, checksum)?.is_ok()
+        {
+            return Ok(candidate);
+        }
+    }
+    Ok(file_size)
+}
+
+/// Length of the record whose header `bytes` start with
+fn header_record_len(bytes: &[u8]) -> u64 {
+    let key_len = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    let val_len = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+    record_len(key_len as usize, val_len as usize)
+}
+
+/// Whether `record`, header included, matches its checksum
+fn record_matches(record: &[u8], checksum: ChecksumAlgorithm) -> bool {
+    let stored = u32::from_le_bytes(record[..4].try_into().unwrap());
+    let namespace = u32::from_le_bytes(record[4..8].try_into().unwrap());
+    checksum.checksum(namespace, &record[HEADER_LEN as usize..]) == stored
+}
+
+/// False when the lengths of the record at `offset` run past the end of the file. A header cut
+/// off by the end of the file is left to the next scan, like a torn write
+fn next_header_fits(f: &mut BufReader<File>, offset: u64, file_size: u64) -> io::Result<bool> {
+    if file_size - offset < HEADER_LEN {
+        return Ok(true);
+    }
+    let mut header = [0; HEADER_LEN as usize];
+    f.seek(SeekFrom::Start(offset))?;
+    f.read_exact(&mut header)?;
+    Ok(header_record_len(&header) <= file_size - offset)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{repair, repair_assuming, verify, Problem};
+    use crate::merge::MERGE_NAMESPACE;
+    use crate::storage::tests::temp_dir;
+    use crate::transaction::TRANSACTION_NAMESPACE;
+    use crate::{record_len, ActionKV, ChecksumAlgorithm, Options, FILE_HEADER_LEN};
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    pub fn test_verify_clean_log() {
+        let dir = temp_dir();
+        let path = &dir.join("log");
+
+        let mut akv = ActionKV::open(path).unwrap();
+        akv.insert(b"a", b"1").unwrap();
+        akv.insert(b"a", b"2").unwrap();
+        akv.insert(b"b", b"3").unwrap();
+        akv.delete(b"b").unwrap();
+        let mut tx = akv.begin();
+        tx.insert(b"a", b"4");
+        tx.insert(b"c", b"5");
+        akv.commit(tx).unwrap();
+
+        let report = verify(path).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.records, 6);
+        assert_eq!(report.live_records, 2);
+        assert_eq!(report.dead_records, 4);
+        assert_eq!(report.duplicate_keys, 2);
+    }
+
+    #[test]
+    pub fn test_repair_skips_bad_records() {
+        let dir = temp_dir();
+        let path = &dir.join("log");
+        let out = &dir.join("repaired");
+
+        let mut akv = ActionKV::open(path).unwrap();
+        akv.insert(b"first", b"one").unwrap();
+        akv.insert(b"second", b"two").unwrap();
+        akv.insert(b"third", b"three").unwrap();
+        drop(akv);
+
+        // Flip a byte of the second value and leave half a record at the end
+        let second = FILE_HEADER_LEN + record_len(5, 3);
+        let mut f = OpenOptions::new().write(true).open(path).unwrap();
+        f.seek(SeekFrom::Start(second + record_len(6, 2))).unwrap();
+        f.write_all(b"X").unwrap();
+        f.seek(SeekFrom::End(0)).unwrap();
+        f.write_all(&[9, 9, 9, 9, 0, 0, 0, 0, 200, 0, 0, 0, 0, 0, 0, 0, 1])
+            .unwrap();
+        drop(f);
+
+        let report = verify(path).unwrap();
+        assert_eq!(report.records, 2);
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::ChecksumMismatch { offset: second },
+                Problem::PastEndOfFile {
+                    offset: second + record_len(6, 3) + record_len(5, 5),
+                    record_len: record_len(200, 0),
+                },
+            ]
+        );
+
+        let mut akv = ActionKV::open(path).unwrap();
+        assert!(akv.load().is_err());
+
+        let repaired = repair(path, out).unwrap();
+        assert_eq!(repaired, report);
+        assert!(verify(out).unwrap().is_clean());
+
+        let mut akv = ActionKV::open(out).unwrap();
+        akv.load().unwrap();
+        assert_eq!(akv.get(b"first").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(akv.get(b"second").unwrap(), None);
+        assert_eq!(akv.get(b"third").unwrap(), Some(b"three".to_vec()));
+
+        assert!(repair(path, path).is_err());
+    }
+
+    #[test]
+    pub fn test_repair_drops_whole_damaged_batch() {
+        let dir = temp_dir();
+        let path = &dir.join("log");
+        let out = &dir.join("repaired");
+
+        let mut akv = ActionKV::open(path).unwrap();
+        akv.insert(b"before", b"0").unwrap();
+        let batch = FILE_HEADER_LEN + record_len(6, 1);
+        let mut tx = akv.begin();
+        tx.insert(b"a", b"1");
+        tx.insert(b"b", b"2");
+        akv.commit(tx).unwrap();
+        let after = batch + record_len(0, (record_len(1, 1) * 2) as usize);
+        akv.insert(b"after", b"3").unwrap();
+        drop(akv);
+
+        // Only the write of "a" is damaged, the one of "b" still passes its own checksum
+        let mut f = OpenOptions::new().write(true).open(path).unwrap();
+        f.seek(SeekFrom::Start(batch + record_len(0, 0) + record_len(1, 0)))
+            .unwrap();
+        f.write_all(b"X").unwrap();
+        drop(f);
+
+        let report = repair(path, out).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![Problem::ChecksumMismatch { offset: batch }]
+        );
+        assert_eq!(report.skipped_bytes, after - batch);
+        assert_eq!(report.records, 2);
+
+        let mut akv = ActionKV::open(out).unwrap();
+        akv.load().unwrap();
+        assert_eq!(akv.get(b"a").unwrap(), None);
+        assert_eq!(akv.get(b"b").unwrap(), None);
+        assert_eq!(akv.get(b"after").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    pub fn test_repair_drops_malformed_records() {
+        let dir = temp_dir();
+        let path = &dir.join("log");
+        let out = &dir.join("repaired");
+
+        let mut akv = ActionKV::open(path).unwrap();
+        akv.insert(b"before", b"0").unwrap();
+        drop(akv);
+        // Both pass their checksums, but the batch holds no whole write and the operand is
+        // shorter than its header
+        let batch = FILE_HEADER_LEN + record_len(6, 1);
+        let operand = batch + record_len(0, 5);
+        let mut f = OpenOptions::new().append(true).open(path).unwrap();
+        let checksum = ChecksumAlgorithm::default();
+        ActionKV::write_record(&mut f, checksum, TRANSACTION_NAMESPACE, b"", b"torn!").unwrap();
+        ActionKV::write_record(&mut f, checksum, MERGE_NAMESPACE, b"k", b"op").unwrap();
+        drop(f);
+        let mut akv = ActionKV::open(path).unwrap();
+        akv.insert(b"after", b"1").unwrap();
+        drop(akv);
+
+        let report = repair(path, out).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::MalformedRecord { offset: batch },
+                Problem::MalformedRecord { offset: operand },
+            ]
+        );
+        assert_eq!(report.records, 2);
+        assert_eq!(report.skipped_bytes, record_len(0, 5) + record_len(1, 2));
+
+        let mut akv = ActionKV::open(out).unwrap();
+        akv.load().unwrap();
+        assert_eq!(akv.get(b"before").unwrap(), Some(b"0".to_vec()));
+        assert_eq!(akv.get(b"after").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    pub fn test_repair_assuming_damaged_file_header() {
+        let dir = temp_dir();
+        let path = &dir.join("log");
+        let out = &dir.join("repaired");
+
+        let options = Options {
+            checksum: ChecksumAlgorithm::XxHash32,
+            ..Options::default()
+        };
+        let mut akv = ActionKV::open_with(path, options).unwrap();
+        akv.insert(b"vlad", b"onis").unwrap();
+        drop(akv);
+        let mut f = OpenOptions::new().write(true).open(path).unwrap();
+        f.write_all(b"XYZ").unwrap();
+        drop(f);
+
+        assert!(verify(path).is_err());
+        assert!(repair(path, out).is_err());
+        let report = repair_assuming(path, out, ChecksumAlgorithm::XxHash32).unwrap();
+        assert_eq!(report.problems, vec![Problem::DamagedFileHeader]);
+        assert_eq!(report.records, 1);
+        assert!(verify(out).unwrap().is_clean());
+
+        let mut akv = ActionKV::open(out).unwrap();
+        akv.load().unwrap();
+        assert_eq!(akv.checksum_algorithm(), ChecksumAlgorithm::XxHash32);
+        assert_eq!(akv.get(b"vlad").unwrap(), Some(b"onis".to_vec()));
+    }
+
+    #[test]
+    pub fn test_repair_resyncs_past_large_damage() {
+        let dir = temp_dir();
+        let path = &dir.join("log");
+        let out = &dir.join("repaired");
+
+        let mut akv = ActionKV::open(path).unwrap();
+        akv.insert(b"first", b"1").unwrap();
+        drop(akv);
+        // Longer than a few read ahead windows, with a header claiming a record longer than one
+        let mut garbage = Vec::with_capacity((3 << 20) + 5);
+        let mut x: u32 = 0x9e37_79b9;
+        while garbage.len() < (3 << 20) + 5 {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            garbage.push(x as u8);
+        }
+        garbage[100..116].copy_from_slice(&[0; 16]);
+        garbage[112..116].copy_from_slice(&(2u32 << 20).to_le_bytes());
+        let mut f = OpenOptions::new().append(true).open(path).unwrap();
+        f.write_all(&garbage).unwrap();
+        drop(f);
+        let mut akv = ActionKV::open(path).unwrap();
+        akv.insert(b"second", b"2").unwrap();
+        drop(akv);
+
+        let report = repair(path, out).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.skipped_bytes, garbage.len() as u64);
+        assert_eq!(report.records, 2);
+
+        let mut akv = ActionKV::open(out).unwrap();
+        akv.load().unwrap();
+        assert_eq!(akv.get(b"first").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(akv.get(b"second").unwrap(), Some(b"2".to_vec()));
+    }
+}
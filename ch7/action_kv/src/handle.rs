@@ -0,0 +1,229 @@
+//! Async access to a store. The store moves onto a dedicated I/O thread and every call becomes a
+//! job on a bounded queue, so a blocking read or write never runs on the caller's executor.
+
+use crate::{ActionKV, ByteStr, ByteString};
+use futures::channel::{mpsc, oneshot};
+use futures::executor::block_on_stream;
+use futures::lock::Mutex;
+use futures::SinkExt;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+
+type Job = Box<dyn FnOnce(&mut ActionKV) + Send>;
+
+const IO_THREAD_NAME: &str = "action_kv-io";
+
+/// Cheap to clone, every clone talks to the same I/O thread. The thread stops and drops the store
+/// once the last clone is gone and the queued jobs have run
+#[derive(Debug, Clone)]
+pub struct AsyncHandle {
+    // A single sender shared by all clones, each cloned sender would get a slot of its own and
+    // the queue would no longer be bounded
+    sender: Arc<Mutex<mpsc::Sender<Job>>>,
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the I/O thread has stopped")
+}
+
+/// Whether the current thread is the I/O thread of some handle, where waiting for a job would
+/// wait for the thread itself
+pub(crate) fn on_io_thread() -> bool {
+    thread::current().name() == Some(IO_THREAD_NAME)
+}
+
+impl AsyncHandle {
+    /// Moves `store` onto a new I/O thread. At most `queue` jobs wait for it, callers beyond that
+    /// wait in `call` until a slot frees up
+    pub fn spawn(store: ActionKV, queue: usize) -> io::Result<AsyncHandle> {
+        if queue == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the queue needs room for at least one job",
+            ));
+        }
+
+        // The channel holds its buffer plus one message per sender
+        let (sender, receiver) = mpsc::channel::<Job>(queue - 1);
+        thread::Builder::new()
+            .name(IO_THREAD_NAME.to_string())
+            .spawn(move || {
+                let mut store = store;
+                for job in block_on_stream(receiver) {
+                    job(&mut store);
+                }
+            })?;
+
+        Ok(AsyncHandle {
+            sender: Arc::new(Mutex::new(sender)),
+        })
+    }
+
+    /// Runs `f` on the I/O thread and resolves to its result
+    pub async fn call<F, T>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut ActionKV) -> io::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move |store| {
+            // The caller may have given up waiting, nobody is left to tell
+            let _ = reply.send(f(store));
+        });
+
+        self.sender
+            .lock()
+            .await
+            .send(job)
+            .await
+            .map_err(|_| stopped())?;
+
+        // Cancelled when the job panicked and took the thread down with it
+        result.await.map_err(|_| stopped())?
+    }
+
+    pub async fn get(&self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let key = key.to_vec();
+        self.call(move |store| store.get(&key)).await
+    }
+
+    pub async fn insert(&self, key: &ByteStr, val: &ByteStr) -> io::Result<()> {
+        let key = key.to_vec();
+        let val = val.to_vec();
+        self.call(move |store| store.insert(&key, &val)).await
+    }
+
+    pub async fn delete(&self, key: &ByteStr) -> io::Result<()> {
+        let key = key.to_vec();
+        self.call(move |store| store.delete(&key)).await
+    }
+
+    /// Atomic, no other job runs between reading the counter and writing it back
+    pub async fn increment(&self, key: &ByteStr, delta: i64) -> io::Result<i64> {
+        let key = key.to_vec();
+        self.call(move |store| store.increment(&key, delta)).await
+    }
+
+    pub async fn decrement(&self, key: &ByteStr, delta: i64) -> io::Result<i64> {
+        let key = key.to_vec();
+        self.call(move |store| store.decrement(&key, delta)).await
+    }
+
+    pub async fn append_value(&self, key: &ByteStr, suffix: &ByteStr) -> io::Result<usize> {
+        let key = key.to_vec();
+        let suffix = suffix.to_vec();
+        self.call(move |store| store.append_value(&key, &suffix))
+            .await
+    }
+
+    /// Key-value pairs of the default namespace whose key starts with `prefix`, sorted by key
+    pub async fn scan(&self, prefix: &ByteStr) -> io::Result<Vec<(ByteString, ByteString)>> {
+        let prefix = prefix.to_vec();
+        self.call(move |store| {
+            let mut keys = Vec::new();
+            for key in store.keys() {
+                let key = key?;
+                if key.starts_with(&prefix) {
+                    keys.push(key);
+                }
+            }
+            keys.sort_unstable();
+
+            let mut pairs = Vec::with_capacity(keys.len());
+            for key in keys {
+                if let Some(value) = store.get(&key)? {
+                    pairs.push((key, value));
+                }
+            }
+            Ok(pairs)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::AsyncHandle;
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+    use futures::executor::block_on;
+    use futures::task::noop_waker;
+    use futures::Sink;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::mpsc;
+    use std::task::Context;
+    use std::thread;
+
+    #[test]
+    pub fn test_async_handle() {
+        let storage = MemoryStorage::new();
+        let handle = AsyncHandle::spawn(open_memory(&storage), 4).unwrap();
+
+        block_on(async {
+            handle.insert(b"user:1", b"vlad").await.unwrap();
+            handle.insert(b"user:2", b"ana").await.unwrap();
+            handle.insert(b"order:1", b"book").await.unwrap();
+            handle.delete(b"user:2").await.unwrap();
+
+            assert_eq!(handle.get(b"user:1").await.unwrap(), Some(b"vlad".to_vec()));
+            assert_eq!(handle.get(b"user:2").await.unwrap(), None);
+            assert_eq!(
+                handle.scan(b"user:").await.unwrap(),
+                vec![(b"user:1".to_vec(), b"vlad".to_vec())]
+            );
+            let keys = handle.call(|store| Ok(store.keys().count())).await.unwrap();
+            assert_eq!(keys, 2);
+        });
+
+        // Everything went through the store on the I/O thread
+        drop(handle);
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.get(b"order:1").unwrap(), Some(b"book".to_vec()));
+
+        assert!(AsyncHandle::spawn(akv, 0).is_err());
+    }
+
+    #[test]
+    pub fn test_full_queue_applies_backpressure() {
+        let handle = AsyncHandle::spawn(open_memory(&MemoryStorage::new()), 2).unwrap();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // Hold the I/O thread until the queue has been filled
+        let (started, running) = mpsc::channel();
+        let (release, gate) = mpsc::channel::<()>();
+        let blocker = handle.clone();
+        let blocked = thread::spawn(move || {
+            block_on(blocker.call(move |_| {
+                started.send(()).unwrap();
+                gate.recv().unwrap();
+                Ok(())
+            }))
+        });
+        running.recv().unwrap();
+
+        let mut first = Box::pin(handle.insert(b"a", b"1"));
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        {
+            let mut sender = handle.sender.try_lock().unwrap();
+            assert!(Pin::new(&mut *sender).poll_ready(&mut cx).is_ready());
+        }
+
+        // The second job fills the queue, its caller keeps the sender until a slot frees up and
+        // everybody else waits behind it
+        let mut second = Box::pin(handle.insert(b"b", b"2"));
+        let mut third = Box::pin(handle.insert(b"c", b"3"));
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        assert!(third.as_mut().poll(&mut cx).is_pending());
+        assert!(handle.sender.try_lock().is_none());
+
+        release.send(()).unwrap();
+        blocked.join().unwrap().unwrap();
+        block_on(first).unwrap();
+        block_on(second).unwrap();
+        block_on(third).unwrap();
+        assert_eq!(block_on(handle.get(b"c")).unwrap(), Some(b"3".to_vec()));
+    }
+}
@@ -0,0 +1,730 @@
+//! Key to offset index of one namespace. By default it is a `HashMap`, which caps a store at the
+//! keys that fit in memory. In spill mode only the latest writes stay in memory, older ones are
+//! flushed into sorted runs on disk that are found again through a sparse summary. A lookup only
+//! reads the runs whose Bloom filter lets its key through.
+
+use crate::bloom::{BloomFilter, FALSE_POSITIVE_RATE};
+use crate::{ByteStr, ByteString};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Every run entry is: key_len(u32), key([u8; key_len]), offset(u64), len(u64), operands(u32)
+const TOMBSTONE: u64 = u64::MAX;
+/// Run entries between two keys of the sparse summary
+const SUMMARY_INTERVAL: u64 = 128;
+/// Runs are merged into one once there are this many, which bounds the runs a lookup may visit
+const MAX_RUNS: usize = 8;
+/// Tells the spill directories of stores in this process apart
+static NEXT_STORE: AtomicU64 = AtomicU64::new(0);
+
+/// Where the index of a store lives, see `Options::index`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum IndexMode {
+    /// Everything in a `HashMap`
+    #[default]
+    Memory,
+    /// At most `memory_entries` recent writes per namespace in memory, the rest in sorted runs
+    /// inside `dir`. Stores can share `dir`, each one keeps its runs in a locked subdirectory
+    /// of its own. Subdirectories left behind by a store that did not close are removed by the
+    /// next store that opens
+    Spill { dir: PathBuf, memory_entries: usize },
+}
+
+#[derive(Debug)]
+pub(crate) struct SpillDir {
+    /// Subdirectory of the store
+    dir: PathBuf,
+    id: String,
+    /// Held locked while the store is open, `None` once it is being removed
+    lock: Option<File>,
+    memory_entries: usize,
+    next_run: AtomicU64,
+}
+
+impl SpillDir {
+    fn create(parent: &Path, memory_entries: usize) -> io::Result<SpillDir> {
+        fs::create_dir_all(parent)?;
+        remove_abandoned(parent)?;
+
+        let id = format!(
+            "{}-{}",
+            process::id(),
+            NEXT_STORE.fetch_add(1, Ordering::Relaxed)
+        );
+        let dir = parent.join(format!("store-{}", id));
+        fs::create_dir(&dir)?;
+        let lock = File::create(dir.join("lock"))?;
+        lock.lock()?;
+
+        Ok(SpillDir {
+            dir,
+            id,
+            lock: Some(lock),
+            memory_entries: memory_entries.max(1),
+            next_run: AtomicU64::new(0),
+        })
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        drop(self.lock.take());
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Removes the subdirectories of `dir` whose lock nobody holds any more
+fn remove_abandoned(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with("store-") {
+            continue;
+        }
+        let lock = match File::open(entry.path().join("lock")) {
+            Ok(lock) => lock,
+            // Still being created, or already being removed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if lock.try_lock().is_ok() {
+            drop(lock);
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates the indexes of new namespaces in the mode the store was opened with
+#[derive(Debug, Clone)]
+pub(crate) struct IndexConfig(Option<Arc<SpillDir>>);
+
+impl IndexConfig {
+    pub(crate) fn new(mode: &IndexMode) -> io::Result<IndexConfig> {
+        let (dir, memory_entries) = match mode {
+            IndexMode::Memory => return Ok(IndexConfig(None)),
+            IndexMode::Spill {
+                dir,
+                memory_entries,
+            } => (dir, *memory_entries),
+        };
+
+        Ok(IndexConfig(Some(Arc::new(SpillDir::create(
+            dir,
+            memory_entries,
+        )?))))
+    }
+
+    pub(crate) fn new_index(&self) -> Index {
+        match &self.0 {
+            None => Index {
+                backend: Backend::Memory(HashMap::new()),
+                live_bytes: 0,
+            },
+            Some(spill) => Index {
+                backend: Backend::Spilled(SpilledIndex {
+                    spill: Arc::clone(spill),
+                    memtable: BTreeMap::new(),
+                    runs: Vec::new(),
+                    len: 0,
+                }),
+                live_bytes: 0,
+            },
+        }
+    }
+}
+
+/// Where the current record of a key is, and how many bytes of the log its value takes: the
+/// record itself and, for a merge operand, the records it folds onto
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Slot {
+    pub(crate) position: u64,
+    pub(crate) len: u64,
+    /// Merge operands in front of the last full value, 0 for a plain record
+    pub(crate) operands: u32,
+}
+
+#[derive(Debug)]
+pub(crate) struct Index {
+    backend: Backend,
+    /// Sum of the lengths of every slot
+    live_bytes: u64,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Memory(HashMap<ByteString, Slot>),
+    Spilled(SpilledIndex),
+}
+
+type Entry = (ByteString, u64);
+
+/// Entries of an index, a snapshot that does not borrow it. The order is only defined in spill
+/// mode, where keys come out sorted
+pub(crate) type Entries = Box<dyn Iterator<Item = io::Result<Entry>> + Send>;
+
+/// Keys of a namespace, see `ActionKV::keys_in`
+pub type Keys = Box<dyn Iterator<Item = io::Result<ByteString>> + Send>;
+
+impl Index {
+    pub(crate) fn len(&self) -> usize {
+        match &self.backend {
+            Backend::Memory(map) => map.len(),
+            Backend::Spilled(spilled) => spilled.len,
+        }
+    }
+
+    /// Bytes of the log taken by the values of all the keys
+    pub(crate) fn live_bytes(&self) -> u64 {
+        self.live_bytes
+    }
+
+    pub(crate) fn get(&self, key: &ByteStr) -> io::Result<Option<u64>> {
+        Ok(self.get_slot(key)?.map(|slot| slot.position))
+    }
+
+    pub(crate) fn get_slot(&self, key: &ByteStr) -> io::Result<Option<Slot>> {
+        match &self.backend {
+            Backend::Memory(map) => Ok(map.get(key).copied()),
+            Backend::Spilled(spilled) => spilled.get(key),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, key: &ByteStr) -> io::Result<bool> {
+        Ok(self.get_slot(key)?.is_some())
+    }
+
+    /// Returns the slot `key` had before
+    pub(crate) fn insert(&mut self, key: ByteString, slot: Slot) -> io::Result<Option<Slot>> {
+        let old = match &mut self.backend {
+            Backend::Memory(map) => map.insert(key, slot),
+            Backend::Spilled(spilled) => {
+                let old = spilled.get(&key)?;
+                if old.is_none() {
+                    spilled.len += 1;
+                }
+                spilled.memtable.insert(key, Some(slot));
+                spilled.maybe_flush()?;
+                old
+            }
+        };
+        self.live_bytes += slot.len;
+        self.live_bytes -= old.map_or(0, |old| old.len);
+        Ok(old)
+    }
+
+    pub(crate) fn remove(&mut self, key: &ByteStr) -> io::Result<Option<Slot>> {
+        let old = match &mut self.backend {
+            Backend::Memory(map) => map.remove(key),
+            Backend::Spilled(spilled) => {
+                let old = spilled.get(key)?;
+                if old.is_some() {
+                    spilled.len -= 1;
+                    spilled.memtable.insert(key.to_vec(), None);
+                    spilled.maybe_flush()?;
+                }
+                old
+            }
+        };
+        self.live_bytes -= old.map_or(0, |old| old.len);
+        Ok(old)
+    }
+
+    pub(crate) fn entries(&self) -> Entries {
+        match &self.backend {
+            Backend::Memory(map) => {
+                let entries: Vec<io::Result<Entry>> = map
+                    .iter()
+                    .map(|(key, slot)| Ok((key.clone(), slot.position)))
+                    .collect();
+                Box::new(entries.into_iter())
+            }
+            Backend::Spilled(spilled) => {
+                let live = spilled.merged(true).filter_map(|entry| match entry {
+                    Ok((key, Some(slot))) => Some(Ok((key, slot.position))),
+                    Ok((_, None)) => None,
+                    Err(e) => Some(Err(e)),
+                });
+                Box::new(live)
+            }
+        }
+    }
+}
+
+/// Sorted file of index entries, removed once the last index or iterator using it is gone
+#[derive(Debug)]
+struct Run {
+    path: PathBuf,
+    f: Mutex<File>,
+    len: u64,
+    entries: u64,
+    /// First key of every `SUMMARY_INTERVAL` entries and where it starts
+    summary: Vec<(ByteString, u64)>,
+    /// Every key of the run, tombstones included. Saved next to it at `bloom_path`
+    bloom: BloomFilter,
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(self.bloom_path());
+    }
+}
+
+type RunEntry = (ByteString, Option<Slot>);
+type RunEntries = Box<dyn Iterator<Item = io::Result<RunEntry>> + Send>;
+
+fn read_entry<R: Read>(f: &mut R) -> io::Result<RunEntry> {
+    let key_len = f.read_u32::<LittleEndian>()?;
+    let mut key = vec![0; key_len as usize];
+    f.read_exact(&mut key)?;
+    let position = f.read_u64::<LittleEndian>()?;
+    let len = f.read_u64::<LittleEndian>()?;
+    let operands = f.read_u32::<LittleEndian>()?;
+    let slot = if position == TOMBSTONE {
+        None
+    } else {
+        Some(Slot {
+            position,
+            len,
+            operands,
+        })
+    };
+    Ok((key, slot))
+}
+
+impl Run {
+    /// `capacity` is how many entries there are at most, the filter is sized for it
+    fn write(path: PathBuf, entries: RunEntries, capacity: u64) -> io::Result<Option<Run>> {
+        // Dropping the run deletes its file, also when writing it fails half way
+        let mut run = Run {
+            f: Mutex::new(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&path)?,
+            ),
+            path,
+            len: 0,
+            entries: 0,
+            summary: Vec::new(),
+            bloom: BloomFilter::new(capacity, FALSE_POSITIVE_RATE),
+        };
+
+        {
+            let f = run.f.get_mut().unwrap_or_else(|e| e.into_inner());
+            let mut out = BufWriter::new(&*f);
+            for entry in entries {
+                let (key, slot) = entry?;
+                if run.entries.is_multiple_of(SUMMARY_INTERVAL) {
+                    run.summary.push((key.clone(), run.len));
+                }
+                out.write_u32::<LittleEndian>(key.len() as u32)?;
+                out.write_all(&key)?;
+                out.write_u64::<LittleEndian>(slot.map_or(TOMBSTONE, |slot| slot.position))?;
+                out.write_u64::<LittleEndian>(slot.map_or(0, |slot| slot.len))?;
+                out.write_u32::<LittleEndian>(slot.map_or(0, |slot| slot.operands))?;
+                run.bloom.insert(&key);
+                run.len += 24 + key.len() as u64;
+                run.entries += 1;
+            }
+            out.flush()?;
+        }
+        if run.entries == 0 {
+            return Ok(None);
+        }
+
+        let mut hint = BufWriter::new(File::create(run.bloom_path())?);
+        run.bloom.write_to(&mut hint)?;
+        hint.flush()?;
+        Ok(Some(run))
+    }
+
+    fn bloom_path(&self) -> PathBuf {
+        self.path.with_extension("bloom")
+    }
+
+    /// `None` when the run knows nothing about `key`, `Some(None)` when it holds its tombstone.
+    /// The file is only read when the filter lets `key` through
+    fn get(&self, key: &ByteStr) -> io::Result<Option<Option<Slot>>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self
+            .summary
+            .partition_point(|(first, _)| first.as_slice() <= key);
+        if block == 0 {
+            return Ok(None);
+        }
+        let start = self.summary[block - 1].1;
+        let end = self
+            .summary
+            .get(block)
+            .map_or(self.len, |(_, offset)| *offset);
+
+        let mut buf = vec![0; (end - start) as usize];
+        {
+            let mut f = self.f.lock().unwrap_or_else(|e| e.into_inner());
+            f.seek(SeekFrom::Start(start))?;
+            f.read_exact(&mut buf)?;
+        }
+
+        let mut block = buf.as_slice();
+        while !block.is_empty() {
+            let (entry_key, slot) = read_entry(&mut block)?;
+            match entry_key.as_slice().cmp(key) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Equal => return Ok(Some(slot)),
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the run from the start with a file handle of its own
+    fn entries(run: Arc<Run>) -> RunEntries {
+        let mut reader: Option<BufReader<File>> = None;
+        let mut remaining = run.entries;
+        Box::new(std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+
+            if reader.is_none() {
+                match File::open(&run.path) {
+                    Ok(f) => reader = Some(BufReader::new(f)),
+                    Err(e) => {
+                        remaining = 0;
+                        return Some(Err(e));
+                    }
+                }
+            }
+            let entry = read_entry(reader.as_mut().unwrap());
+            if entry.is_err() {
+                remaining = 0;
+            }
+            Some(entry)
+        }))
+    }
+}
+
+/// Merges sorted sources given oldest first into one sorted stream. When several sources hold
+/// the same key the newest one wins
+struct Merge {
+    sources: Vec<RunEntries>,
+    heads: Vec<Option<RunEntry>>,
+    primed: bool,
+}
+
+impl Merge {
+    fn new(sources: Vec<RunEntries>) -> Merge {
+        let heads = sources.iter().map(|_| None).collect();
+        Merge {
+            sources,
+            heads,
+            primed: false,
+        }
+    }
+
+    fn advance(&mut self, source: usize) -> io::Result<()> {
+        self.heads[source] = self.sources[source].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for Merge {
+    type Item = io::Result<RunEntry>;
+
+    fn next(&mut self) -> Option<io::Result<RunEntry>> {
+        if !self.primed {
+            self.primed = true;
+            for source in 0..self.sources.len() {
+                if let Err(e) = self.advance(source) {
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        let mut newest: Option<usize> = None;
+        for source in 0..self.heads.len() {
+            let key = match &self.heads[source] {
+                None => continue,
+                Some((key, _)) => key,
+            };
+            let wins = match newest {
+                None => true,
+                Some(best) => key <= &self.heads[best].as_ref().unwrap().0,
+            };
+            if wins {
+                newest = Some(source);
+            }
+        }
+
+        let newest = newest?;
+        let entry = self.heads[newest].take().unwrap();
+        for source in 0..self.heads.len() {
+            let same_key = self.heads[source]
+                .as_ref()
+                .is_some_and(|(key, _)| *key == entry.0);
+            if source == newest || same_key {
+                if let Err(e) = self.advance(source) {
+                    return Some(Err(e));
+                }
+            }
+        }
+        Some(Ok(entry))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SpilledIndex {
+    spill: Arc<SpillDir>,
+    /// Latest writes, `None` marks a deleted key that the runs may still hold
+    memtable: BTreeMap<ByteString, Option<Slot>>,
+    /// Oldest first
+    runs: Vec<Arc<Run>>,
+    len: usize,
+}
+
+impl SpilledIndex {
+    fn get(&self, key: &ByteStr) -> io::Result<Option<Slot>> {
+        if let Some(slot) = self.memtable.get(key) {
+            return Ok(*slot);
+        }
+        for run in self.runs.iter().rev() {
+            if let Some(slot) = run.get(key)? {
+                return Ok(slot);
+            }
+        }
+        Ok(None)
+    }
+
+    fn run_path(&self) -> PathBuf {
+        let id = self.spill.next_run.fetch_add(1, Ordering::Relaxed);
+        self.spill
+            .dir
+            .join(format!("run-{}-{:08}", self.spill.id, id))
+    }
+
+    fn maybe_flush(&mut self) -> io::Result<()> {
+        if self.memtable.len() < self.spill.memory_entries {
+            return Ok(());
+        }
+
+        let memtable = std::mem::take(&mut self.memtable);
+        let capacity = memtable.len() as u64;
+        let entries = Box::new(memtable.into_iter().map(Ok));
+        if let Some(run) = Run::write(self.run_path(), entries, capacity)? {
+            self.runs.push(Arc::new(run));
+        }
+
+        if self.runs.len() >= MAX_RUNS {
+            // Nothing is older than all the runs, so their tombstones can go
+            let capacity = self.runs.iter().map(|run| run.entries).sum();
+            let merged = Box::new(self.merged(false).filter(|entry| match entry {
+                Ok((_, slot)) => slot.is_some(),
+                Err(_) => true,
+            }));
+            let run = Run::write(self.run_path(), merged, capacity)?;
+            self.runs = run.into_iter().map(Arc::new).collect();
+        }
+        Ok(())
+    }
+
+    /// Runs, and the memtable when asked, merged into one sorted stream, tombstones included
+    fn merged(&self, with_memtable: bool) -> Merge {
+        let mut sources: Vec<RunEntries> = self
+            .runs
+            .iter()
+            .map(|run| Run::entries(Arc::clone(run)))
+            .collect();
+        if with_memtable {
+            let memtable: Vec<io::Result<RunEntry>> = self
+                .memtable
+                .iter()
+                .map(|(key, slot)| Ok((key.clone(), *slot)))
+                .collect();
+            sources.push(Box::new(memtable.into_iter()));
+        }
+        Merge::new(sources)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Backend, IndexConfig, IndexMode, Slot};
+    use crate::bloom::BloomFilter;
+    use crate::storage::tests::temp_dir;
+    use crate::storage::MemoryStorage;
+    use crate::{ActionKV, Options};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::fs::File;
+    use std::path::Path;
+
+    /// Runs of every store spilling into `dir`
+    fn spilled_runs(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|store| fs::read_dir(store.unwrap().path()).unwrap())
+            .flat_map(|runs| runs.map(|run| run.unwrap().file_name()))
+            .map(|name| name.to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("run-") && !name.ends_with(".bloom"))
+            .count()
+    }
+
+    fn spill_mode(dir: &Path) -> IndexMode {
+        IndexMode::Spill {
+            dir: dir.to_path_buf(),
+            memory_entries: 16,
+        }
+    }
+
+    #[test]
+    pub fn test_spilled_index_matches_map() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        // Left behind by a store that never closed
+        let abandoned = dir.join("store-abandoned");
+        fs::create_dir_all(&abandoned).unwrap();
+        fs::write(abandoned.join("lock"), b"").unwrap();
+        fs::write(abandoned.join("run-abandoned-00000000"), b"").unwrap();
+
+        let config = IndexConfig::new(&spill_mode(dir)).unwrap();
+        assert!(!abandoned.exists());
+        let mut index = config.new_index();
+        let mut expected = HashMap::new();
+        // A second store spilling into the same directory keeps to its own runs
+        let other_config = IndexConfig::new(&spill_mode(dir)).unwrap();
+        let mut other = other_config.new_index();
+
+        // Enough writes for several flushes and merges, overwriting and deleting along the way
+        for i in 0..2000u64 {
+            let key = format!("key:{:04}", (i * 7919) % 600).into_bytes();
+            if i % 5 == 0 {
+                assert_eq!(index.remove(&key).unwrap(), expected.remove(&key));
+            } else {
+                let slot = Slot {
+                    position: i,
+                    len: i % 7,
+                    operands: (i % 3) as u32,
+                };
+                assert_eq!(
+                    index.insert(key.clone(), slot).unwrap(),
+                    expected.insert(key.clone(), slot)
+                );
+            }
+            let slot = Slot {
+                position: u64::MAX - 1,
+                len: 1,
+                operands: 0,
+            };
+            other.insert(key, slot).unwrap();
+        }
+        assert!(spilled_runs(dir) > 0);
+        assert_eq!(index.len(), expected.len());
+        assert_eq!(
+            index.live_bytes(),
+            expected.values().map(|slot| slot.len).sum::<u64>()
+        );
+
+        for i in 0..600u64 {
+            let key = format!("key:{:04}", i).into_bytes();
+            assert_eq!(index.get_slot(&key).unwrap(), expected.get(&key).copied());
+        }
+        assert_eq!(index.get(b"absent").unwrap(), None);
+
+        let entries: Vec<(Vec<u8>, u64)> = index.entries().map(|e| e.unwrap()).collect();
+        let mut sorted: Vec<(Vec<u8>, u64)> = expected
+            .into_iter()
+            .map(|(key, slot)| (key, slot.position))
+            .collect();
+        sorted.sort();
+        assert_eq!(entries, sorted);
+
+        drop(index);
+        drop(config);
+        assert!(spilled_runs(dir) > 0);
+        assert_eq!(other.get(b"key:0001").unwrap(), Some(u64::MAX - 1));
+        drop(other);
+        drop(other_config);
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    pub fn test_runs_skipped_by_their_filters() {
+        let temp = temp_dir();
+        let config = IndexConfig::new(&spill_mode(temp.path())).unwrap();
+        let mut index = config.new_index();
+        // Two flushes of 16, nothing left in memory
+        let key = |i: u32| format!("key:{:04}", i).into_bytes();
+        for i in 0..32 {
+            let slot = Slot {
+                position: i as u64,
+                len: 1,
+                operands: 0,
+            };
+            index.insert(key(i), slot).unwrap();
+        }
+
+        let runs = match &index.backend {
+            Backend::Spilled(spilled) => &spilled.runs,
+            Backend::Memory(_) => unreachable!(),
+        };
+        assert_eq!(runs.len(), 2);
+        for run in runs {
+            let saved = BloomFilter::read_from(&mut File::open(run.bloom_path()).unwrap());
+            assert_eq!(saved.unwrap(), run.bloom);
+            // Any read of the run fails from now on
+            File::create(&run.path).unwrap();
+        }
+
+        assert!(index.get(&key(3)).is_err());
+        let skipped = (1000..2000)
+            .filter(|i| index.get(&key(*i)).unwrap_or(Some(0)).is_none())
+            .count();
+        assert!(skipped > 950, "{} misses skipped the runs", skipped);
+    }
+
+    #[test]
+    pub fn test_store_with_spilled_index() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        let storage = MemoryStorage::new();
+        let options = Options {
+            index: spill_mode(dir),
+            ..Options::default()
+        };
+
+        let mut akv = ActionKV::with_storage(Box::new(storage.clone()), options.clone()).unwrap();
+        akv.load().unwrap();
+        for i in 0..500u32 {
+            akv.insert(format!("{}", i).as_bytes(), &i.to_le_bytes())
+                .unwrap();
+        }
+        akv.delete(b"7").unwrap();
+        akv.compact().unwrap();
+        assert_eq!(akv.stats().live_keys, 499);
+
+        drop(akv);
+        let mut akv = ActionKV::with_storage(Box::new(storage), options).unwrap();
+        akv.load().unwrap();
+        assert_eq!(akv.get(b"7").unwrap(), None);
+        assert_eq!(
+            akv.get(b"321").unwrap(),
+            Some(321u32.to_le_bytes().to_vec())
+        );
+        assert!(akv.contains_key(b"499").unwrap());
+        assert_eq!(akv.keys().count(), 499);
+        assert!(spilled_runs(dir) > 0);
+    }
+}
@@ -1,102 +1,527 @@
 extern crate core;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use crc::crc32;
+use index::{Index, IndexConfig, Slot};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::Write;
 use std::io::{BufWriter, Read, Seek, SeekFrom};
 use std::path::Path;
+use std::time::Instant;
+use storage::{FileStorage, Storage};
+
+pub mod bloom;
+pub mod cache;
+pub mod checksum;
+pub mod codec;
+pub mod dump;
+pub mod fsck;
+pub mod handle;
+pub mod index;
+pub mod limits;
+pub mod merge;
+pub mod merkle;
+pub mod metrics;
+#[cfg(test)]
+mod model;
+pub mod namespace;
+pub mod numeric;
+pub mod quota;
+pub mod secondary;
+pub mod shard;
+pub mod stats;
+pub mod storage;
+pub mod sync;
+pub mod transaction;
+pub mod typed;
+mod upgrade;
+pub mod watch;
+
+pub use checksum::ChecksumAlgorithm;
+pub use index::{IndexMode, Keys};
+pub use limits::Limits;
+pub use namespace::{NamespaceId, DEFAULT_NAMESPACE};
+pub use numeric::OverflowPolicy;
 
 type ByteString = Vec<u8>;
 type ByteStr = [u8];
 
-#[derive(Debug, Serialize, Deserialize)]
+/// checksum, namespace, key_len and val_len, all u32
+const HEADER_LEN: u64 = 16;
+
+/// Every log starts with a file header: magic(b"AKV"), version(u8), checksum algorithm(u8) and
+/// 3 reserved zero bytes. Records follow it
+const FILE_HEADER_LEN: u64 = 8;
+const MAGIC: &[u8; 3] = b"AKV";
+const FORMAT_VERSION: u8 = 1;
+
+fn record_len(key_len: usize, val_len: usize) -> u64 {
+    HEADER_LEN + key_len as u64 + val_len as u64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KeyValuePair {
+    namespace: NamespaceId,
     key: ByteString,
     value: ByteString,
 }
 
+/// Settings used when a store is created. Those recorded in the file header of an existing log
+/// take precedence
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub checksum: ChecksumAlgorithm,
+    /// Bytes of recently read and written values kept in memory, 0 disables the cache
+    pub value_cache: usize,
+    pub limits: Limits,
+    pub index: IndexMode,
+    /// What counters do when `increment` takes them past the range of an `i64`
+    pub overflow: OverflowPolicy,
+    /// Largest size of the log in bytes, writes past it are refused. `None` for no quota
+    pub quota: Option<u64>,
+}
+
 #[derive(Debug)]
 pub struct ActionKV {
-    f: File,
-    index: HashMap<ByteString, u64>,
+    f: Box<dyn Storage>,
+    checksum: ChecksumAlgorithm,
+    limits: Limits,
+    overflow: OverflowPolicy,
+    quota: Option<u64>,
+    /// Error of the write that left the store read-only
+    read_only: Option<String>,
+    /// Whether the index holds the whole log, `compact` would drop what it does not
+    loaded: bool,
+    index: HashMap<NamespaceId, Index>,
+    index_config: IndexConfig,
+    namespaces: HashMap<String, NamespaceId>,
+    next_namespace: NamespaceId,
+    secondary: HashMap<String, secondary::SecondaryIndex>,
+    merkle: HashMap<NamespaceId, merkle::MerkleTree>,
+    merge_operators: merge::MergeOperators,
+    watchers: Vec<watch::Watcher>,
+    counters: stats::Counters,
+    metrics: metrics::Metrics,
+    cache: cache::ValueCache,
+    bloom: bloom::BloomFilter,
 }
 
 impl ActionKV {
     pub fn open(path: &Path) -> io::Result<ActionKV> {
-        let f = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(path)?;
+        ActionKV::open_with(path, Options::default())
+    }
+
+    pub fn open_with(path: &Path, options: Options) -> io::Result<ActionKV> {
+        ActionKV::with_storage(Box::new(FileStorage::open(path)?), options)
+    }
 
-        let index = HashMap::new();
-        Ok(ActionKV { f, index })
+    /// Opens a store on any storage backend, such as a `storage::MemoryStorage` in tests
+    /// A log written before the file header existed is rewritten in the current format first
+    pub fn with_storage(mut f: Box<dyn Storage>, options: Options) -> io::Result<ActionKV> {
+        let empty = f.size()? == 0;
+        let checksum = if empty {
+            ActionKV::write_file_header(&mut f, options.checksum)?;
+            f.flush()?;
+            options.checksum
+        } else if upgrade::upgrade_baseline_log(&mut f, options.checksum)? {
+            options.checksum
+        } else {
+            ActionKV::read_file_header(&mut f)?
+        };
+        let log_len = f.size()?;
+
+        let mut akv = ActionKV {
+            f,
+            checksum,
+            limits: options.limits,
+            overflow: options.overflow,
+            quota: options.quota,
+            read_only: None,
+            // A new log has nothing to replay
+            loaded: empty,
+            index: HashMap::new(),
+            index_config: IndexConfig::new(&options.index)?,
+            namespaces: HashMap::new(),
+            next_namespace: DEFAULT_NAMESPACE + 1,
+            secondary: HashMap::new(),
+            merkle: HashMap::new(),
+            merge_operators: merge::MergeOperators::default(),
+            watchers: Vec::new(),
+            counters: stats::Counters::new(log_len),
+            metrics: metrics::Metrics::default(),
+            cache: cache::ValueCache::new(options.value_cache),
+            bloom: bloom::BloomFilter::default(),
+        };
+        akv.reset_namespaces();
+        Ok(akv)
     }
 
+    /// Rebuilds every index by replaying the log from the start. A torn record at the end of
+    /// the log, left by a crash in the middle of a write, is cut off
     pub fn load(&mut self) -> io::Result<()> {
+        self.loaded = false;
+        self.reset_namespaces();
+        self.cache.clear();
+
+        let log_len = self.f.size()?;
         let mut f = io::BufReader::new(&mut self.f);
+        f.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+        let mut total_records = 0;
 
         loop {
-            let position = f.seek(SeekFrom::Current(0))?;
-            let maybe_kv = ActionKV::process_record(&mut f);
+            let position = f.stream_position()?;
+            let maybe_kv = ActionKV::process_record(&mut f, self.checksum, self.limits);
             let kv = match maybe_kv {
                 Ok(kv) => kv,
                 Err(e) => match e.kind() {
                     io::ErrorKind::UnexpectedEof => {
+                        if position < log_len {
+                            f.into_inner().truncate(position)?;
+                        }
                         break;
                     }
-                    _ => return Err(e),
+                    _ => {
+                        self.metrics.read_failed(&e);
+                        return Err(e);
+                    }
                 },
             };
+            total_records += 1;
 
-            self.index.insert(kv.key, position);
+            let records = if kv.namespace == transaction::TRANSACTION_NAMESPACE {
+                transaction::split_batch(position, kv, self.checksum, self.limits)?
+            } else {
+                vec![(position, kv)]
+            };
+
+            for (position, kv) in records {
+                if kv.namespace == namespace::CATALOG_NAMESPACE {
+                    namespace::apply_catalog_record(
+                        &mut self.namespaces,
+                        &mut self.index,
+                        &self.index_config,
+                        &mut self.next_namespace,
+                        &kv,
+                    )?;
+                    continue;
+                }
+
+                // An operand is indexed under the namespace it merges into
+                let namespace = if kv.namespace == merge::MERGE_NAMESPACE {
+                    merge::Operand::decode(&kv.value)?.namespace
+                } else {
+                    kv.namespace
+                };
+
+                // Records of a dropped namespace have no index left to land in
+                let index = match self.index.get_mut(&namespace) {
+                    Some(index) => index,
+                    None => continue,
+                };
+
+                if kv.value.is_empty() {
+                    index.remove(&kv.key)?;
+                    continue;
+                }
+                let mut slot = Slot {
+                    position,
+                    len: record_len(kv.key.len(), kv.value.len()),
+                    operands: 0,
+                };
+                if kv.namespace == merge::MERGE_NAMESPACE {
+                    // An operand keeps the records it folds onto alive
+                    let old = index.get_slot(&kv.key)?;
+                    slot.len += old.map_or(0, |old| old.len);
+                    slot.operands = old.map_or(0, |old| old.operands) + 1;
+                }
+                index.insert(kv.key, slot)?;
+            }
         }
 
+        self.recount(total_records)?;
+        self.rebuild_bloom_filter()?;
+        self.rebuild_secondary_indexes()?;
+        self.rebuild_merkle_trees()?;
+        self.loaded = true;
         Ok(())
     }
 
-    /// Format of a record is: checksum(u32), key_len(u32), val_len(u32), key([u8, key_len]),
-    /// value([u8, val_len])
-    fn process_record<R: Read>(f: &mut R) -> io::Result<KeyValuePair> {
+    /// Format of a record is: checksum(u32), namespace(u32), key_len(u32), val_len(u32),
+    /// key([u8, key_len]), value([u8, val_len]). The checksum covers the namespace, key and value.
+    /// Lengths beyond `limits` are rejected before anything is allocated for them
+    fn process_record<R: Read>(
+        f: &mut R,
+        algorithm: ChecksumAlgorithm,
+        limits: Limits,
+    ) -> io::Result<KeyValuePair> {
         let saved_check_sum = f.read_u32::<LittleEndian>()?;
+        let namespace = f.read_u32::<LittleEndian>()?;
         let key_len = f.read_u32::<LittleEndian>()?;
         let val_len = f.read_u32::<LittleEndian>()?;
-        let data_len = key_len + val_len;
+        limits.check_read(key_len, val_len)?;
+        let data_len = key_len as u64 + val_len as u64;
 
         let mut data = ByteString::with_capacity(data_len as usize);
 
-        let _entry_size = f.by_ref().take(data_len as u64).read_to_end(&mut data)?;
-
-        debug_assert_eq!(data.len() as usize, data_len as usize); // Runtime check for debug builds
+        let entry_size = f.by_ref().take(data_len).read_to_end(&mut data)?;
+        if (entry_size as u64) < data_len {
+            // A torn record at the end of the log reads the same as a missing one
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "record runs past the end of the log",
+            ));
+        }
 
-        let check_sum = crc32::checksum_ieee(&data);
+        let check_sum = algorithm.checksum(namespace, &data);
         if check_sum != saved_check_sum {
-            panic!(
-                "Data corruption encountered({:08x} != {:08x}",
-                check_sum, saved_check_sum
-            );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                checksum::ChecksumMismatch {
+                    stored: saved_check_sum,
+                    computed: check_sum,
+                },
+            ));
         }
 
         let value = data.split_off(key_len as usize); // Split a Vec in 2 an n
         let key = data;
 
-        Ok(KeyValuePair { key, value })
+        Ok(KeyValuePair {
+            namespace,
+            key,
+            value,
+        })
+    }
+
+    fn write_file_header<W: Write>(f: &mut W, algorithm: ChecksumAlgorithm) -> io::Result<()> {
+        f.write_all(MAGIC)?;
+        f.write_u8(FORMAT_VERSION)?;
+        f.write_u8(algorithm.id())?;
+        f.write_all(&[0; 3])?;
+        Ok(())
+    }
+
+    fn read_file_header<R: Read + Seek>(f: &mut R) -> io::Result<ChecksumAlgorithm> {
+        let mut header = [0; FILE_HEADER_LEN as usize];
+        f.seek(SeekFrom::Start(0))?;
+        f.read_exact(&mut header)?;
+
+        if &header[..3] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an ActionKV log, the magic bytes are missing",
+            ));
+        }
+        if header[3] != FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported format version {}", header[3]),
+            ));
+        }
+
+        ChecksumAlgorithm::from_id(header[4])
+    }
+
+    pub fn checksum_algorithm(&self) -> ChecksumAlgorithm {
+        self.checksum
+    }
+
+    /// Makes every write so far durable. Writes only reach the operating system otherwise
+    pub fn sync(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        self.f.sync()?;
+        self.metrics.synced(started.elapsed());
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        self.get_in(DEFAULT_NAMESPACE, key)
+    }
+
+    pub fn get_in(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+    ) -> io::Result<Option<ByteString>> {
+        self.metrics.got();
+        let index = self.namespace_index(namespace)?;
+        if !self.may_contain(namespace, key) {
+            return Ok(None);
+        }
+        let position = match index.get(key)? {
+            None => return Ok(None),
+            Some(position) => position,
+        };
+        if let Some(value) = self.cache.get(namespace, key) {
+            return Ok(Some(value));
+        }
+
+        let value = self.value_at(namespace, position)?;
+        self.cache.insert(namespace, key, &value);
+        Ok(Some(value))
+    }
+
+    pub fn get_at(&mut self, position: u64) -> io::Result<KeyValuePair> {
+        let mut f = io::BufReader::new(&mut self.f);
+        f.seek(SeekFrom::Start(position))?;
+        let kv = ActionKV::process_record(&mut f, self.checksum, self.limits);
+        if let Err(e) = &kv {
+            self.metrics.read_failed(e);
+        }
+        kv
+    }
+
+    pub fn contains_key(&self, key: &ByteStr) -> io::Result<bool> {
+        self.contains_key_in(DEFAULT_NAMESPACE, key)
+    }
+
+    pub fn contains_key_in(&self, namespace: NamespaceId, key: &ByteStr) -> io::Result<bool> {
+        if !self.may_contain(namespace, key) {
+            return Ok(false);
+        }
+        match self.index.get(&namespace) {
+            None => Ok(false),
+            Some(index) => index.contains_key(key),
+        }
+    }
+
+    /// Keys currently present in the index, in no particular order
+    pub fn keys(&self) -> Keys {
+        self.keys_in(DEFAULT_NAMESPACE)
+    }
+
+    /// Keys of one namespace, an unknown namespace has no keys. The keys are a snapshot, the
+    /// store can be written to while they are being iterated
+    pub fn keys_in(&self, namespace: NamespaceId) -> Keys {
+        match self.index.get(&namespace) {
+            None => Box::new(std::iter::empty()),
+            Some(index) => Box::new(index.entries().map(|entry| entry.map(|(key, _)| key))),
+        }
     }
 
+    /// A deletion is recorded by appending a tombstone, a record with an empty value, so that
+    /// `load` drops the key again when it replays the log
+    pub fn delete(&mut self, key: &ByteStr) -> io::Result<()> {
+        self.delete_in(DEFAULT_NAMESPACE, key)
+    }
+
+    pub fn delete_in(&mut self, namespace: NamespaceId, key: &ByteStr) -> io::Result<()> {
+        self.namespace_index(namespace)?;
+        let position = self.append(namespace, key, b"")?;
+        self.index_delete(namespace, key, position)?;
+
+        self.maybe_compact();
+        Ok(())
+    }
+
+    /// Brings the indexes, the cache and the watchers up to date with a tombstone written at
+    /// `position`
+    fn index_delete(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        position: u64,
+    ) -> io::Result<()> {
+        if let Some(old) = self.namespace_index_mut(namespace)?.remove(key)? {
+            self.release_record(old);
+        }
+        self.cache.remove(namespace, key);
+        self.metrics.deleted();
+        self.update_secondary_indexes(namespace, key, None);
+        self.update_merkle_trees(namespace, key, None);
+        self.notify_watchers(position, namespace, key, b"");
+        Ok(())
+    }
+
+    /// An empty `val` is indistinguishable from a tombstone and reads back as a deleted key
     pub fn insert(&mut self, key: &ByteStr, val: &ByteStr) -> io::Result<()> {
-        let position = self.insert_ignore_index(key, val)?;
-        self.index.insert(key.to_vec(), position);
+        self.insert_in(DEFAULT_NAMESPACE, key, val)
+    }
+
+    pub fn insert_in(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        val: &ByteStr,
+    ) -> io::Result<()> {
+        self.namespace_index(namespace)?;
+        let position = self.append(namespace, key, val)?;
+        self.index_insert(namespace, key, val, position)?;
+
+        self.maybe_compact();
+        Ok(())
+    }
 
+    /// Like `index_delete`, for a record holding `val` written at `position`
+    fn index_insert(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        val: &ByteStr,
+        position: u64,
+    ) -> io::Result<()> {
+        let len = record_len(key.len(), val.len());
+        self.counters.live_bytes += len;
+        if let Some(old) = self.namespace_index_mut(namespace)?.insert(
+            key.to_vec(),
+            Slot {
+                position,
+                len,
+                operands: 0,
+            },
+        )? {
+            self.release_record(old);
+        }
+        self.cache.insert(namespace, key, val);
+        self.metrics.inserted();
+        self.bloom_insert(namespace, key)?;
+        self.update_secondary_indexes(namespace, key, Some(val));
+        self.update_merkle_trees(namespace, key, Some(val));
+        self.notify_watchers(position, namespace, key, val);
         Ok(())
     }
 
     pub fn insert_ignore_index(&mut self, key: &ByteStr, val: &ByteStr) -> io::Result<u64> {
-        let mut f = BufWriter::new(&mut self.f);
+        self.append(DEFAULT_NAMESPACE, key, val)
+    }
+
+    fn append(&mut self, namespace: NamespaceId, key: &ByteStr, val: &ByteStr) -> io::Result<u64> {
+        self.limits.check_write(key, val)?;
+        self.check_room(record_len(key.len(), val.len()))?;
+        let log_len = self.counters.log_len;
+        match ActionKV::write_flushed(&mut self.f, self.checksum, namespace, key, val) {
+            Ok(position) => {
+                let len = record_len(key.len(), val.len());
+                self.counters.appended(position + len);
+                self.metrics.wrote(len);
+                Ok(position)
+            }
+            Err(e) => Err(self.append_failed(log_len, e)),
+        }
+    }
+
+    fn write_flushed(
+        f: &mut Box<dyn Storage>,
+        algorithm: ChecksumAlgorithm,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        val: &ByteStr,
+    ) -> io::Result<u64> {
+        let mut f = BufWriter::new(f);
+        let position = ActionKV::write_record(&mut f, algorithm, namespace, key, val)?;
+        // Dropping the buffer would flush it too, but swallow the error
+        f.flush()?;
+        Ok(position)
+    }
+
+    fn write_record<W: Write + Seek>(
+        f: &mut W,
+        algorithm: ChecksumAlgorithm,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        val: &ByteStr,
+    ) -> io::Result<u64> {
         let key_len = key.len();
         let val_len = val.len();
         let mut tmp = ByteString::with_capacity(key_len + val_len);
@@ -109,28 +534,119 @@ impl ActionKV {
             tmp.push(byte.to_owned());
         }
 
-        let checksum = crc32::checksum_ieee(&tmp);
+        let checksum = algorithm.checksum(namespace, &tmp);
 
         let next_byte = SeekFrom::End(0);
-        let current_position = f.seek(SeekFrom::Current(0))?;
-        f.seek(next_byte)?;
+        let current_position = f.seek(next_byte)?;
         f.write_u32::<LittleEndian>(checksum)?;
+        f.write_u32::<LittleEndian>(namespace)?;
         f.write_u32::<LittleEndian>(key_len as u32)?;
         f.write_u32::<LittleEndian>(val_len as u32)?;
         f.write_all(&tmp)?;
         Ok(current_position)
     }
+
+    fn namespace_index(&self, namespace: NamespaceId) -> io::Result<&Index> {
+        self.index
+            .get(&namespace)
+            .ok_or_else(|| namespace::unknown(namespace))
+    }
+
+    fn namespace_index_mut(&mut self, namespace: NamespaceId) -> io::Result<&mut Index> {
+        self.index
+            .get_mut(&namespace)
+            .ok_or_else(|| namespace::unknown(namespace))
+    }
+
+    /// Rewrites the log so that it only holds the latest version of every live key. Stale
+    /// versions, tombstones and the records of dropped namespaces are left behind. Fails until
+    /// `load` has replayed the log
+    pub fn compact(&mut self) -> io::Result<()> {
+        if !self.loaded {
+            return Err(io::Error::other(
+                "the log has not been loaded, compacting it would drop its records",
+            ));
+        }
+        let started = Instant::now();
+        let mut out = BufWriter::new(self.f.scratch()?);
+        ActionKV::write_file_header(&mut out, self.checksum)?;
+
+        let mut names: Vec<(String, NamespaceId)> = self
+            .namespaces
+            .iter()
+            .map(|(name, id)| (name.clone(), *id))
+            .collect();
+        names.sort_by_key(|(_, id)| *id);
+
+        let mut new_index = HashMap::new();
+        let mut records = 0;
+        for (name, id) in names {
+            if id != DEFAULT_NAMESPACE {
+                records += 1;
+                ActionKV::write_record(
+                    &mut out,
+                    self.checksum,
+                    namespace::CATALOG_NAMESPACE,
+                    name.as_bytes(),
+                    &id.to_le_bytes(),
+                )?;
+            }
+
+            let mut index = self.index_config.new_index();
+            let fold = self.merge_operators.contains(id);
+            for entry in self.index[&id].entries() {
+                let (key, position) = entry?;
+                let slot = if fold {
+                    // Operands are folded into a plain value here
+                    let value = self.value_at(id, position)?;
+                    let new_position =
+                        ActionKV::write_record(&mut out, self.checksum, id, &key, &value)?;
+                    records += 1;
+                    Slot {
+                        position: new_position,
+                        len: record_len(key.len(), value.len()),
+                        operands: 0,
+                    }
+                } else {
+                    // Without an operator they are carried over as they are
+                    let (slot, copied) = self.copy_chain(&mut out, position)?;
+                    records += copied;
+                    slot
+                };
+                index.insert(key, slot)?;
+            }
+            new_index.insert(id, index);
+        }
+
+        let mut scratch = out.into_inner().map_err(|e| e.into_error())?;
+        let log_len = scratch.size()?;
+        let syncing = Instant::now();
+        scratch.sync()?;
+        self.metrics.synced(syncing.elapsed());
+        self.f.replace(scratch)?;
+        self.index = new_index;
+        self.cache.clear();
+        self.rebuild_bloom_filter()?;
+        self.counters.compacted(records, log_len);
+        self.metrics.wrote(log_len);
+        self.metrics.compacted(started.elapsed());
+        // The fresh log got written, so there is room again
+        self.read_only = None;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::ActionKV;
-    use crate::ByteStr;
+    use crate::storage::MemoryStorage;
+    use crate::{ByteStr, ChecksumAlgorithm, Limits, Options, DEFAULT_NAMESPACE, FILE_HEADER_LEN};
     use byteorder::{LittleEndian, WriteBytesExt};
-    use std::fs::{File, OpenOptions};
+    use std::fs::File;
+    use std::io;
     use std::io::{Seek, SeekFrom, Write};
     use std::path::Path;
-    use std::{fs, io};
 
     #[test]
     pub fn open_file() {
@@ -140,15 +656,11 @@ pub mod tests {
 
     #[test]
     pub fn test_process_record() {
-        let path = Path::new("test_data/test_file");
-        if path.exists() {
-            fs::remove_file(path).expect("Failed to delete file");
-        }
-        let written = write_hardcoded_bitcask(path, b"vlad", b"onis");
-        assert!(written.is_ok());
-
-        let mut f = File::open(path).unwrap();
-        let data = ActionKV::process_record(&mut f).unwrap();
+        // Written by `write_hardcoded_bitcask` for "vlad" and "onis"
+        let mut f = File::open(Path::new("test_data/test_file")).unwrap();
+        f.seek(SeekFrom::Start(FILE_HEADER_LEN)).unwrap();
+        let data =
+            ActionKV::process_record(&mut f, ChecksumAlgorithm::Crc32, Limits::default()).unwrap();
 
         assert_eq!(data.key, b"vlad");
         assert_eq!(data.value, b"onis");
@@ -156,35 +668,29 @@ pub mod tests {
 
     #[test]
     pub fn test_load() {
-        let path = Path::new("test_data/test_file");
-        if path.exists() {
-            fs::remove_file(path).expect("Failed to delete file");
-        }
-        let written = write_hardcoded_bitcask(path, b"vlad", b"onis");
+        let mut storage = MemoryStorage::new();
+        let written = write_hardcoded_bitcask(&mut storage, b"vlad", b"onis");
         assert!(written.is_ok());
-        let written = write_hardcoded_bitcask(path, b"test", b"data");
+        let written = write_hardcoded_bitcask(&mut storage, b"test", b"data");
         assert!(written.is_ok());
 
-        let mut akv = ActionKV::open(path).unwrap();
+        let mut akv = ActionKV::with_storage(Box::new(storage), Options::default()).unwrap();
         akv.f
             .seek(SeekFrom::Start(0))
             .expect("Could not move cursor");
         assert!(akv.load().is_ok());
-        let value = akv.index.get("vlad".as_bytes()).unwrap();
-        assert_eq!(&0, value);
+        let value = akv.index[&DEFAULT_NAMESPACE]
+            .get("vlad".as_bytes())
+            .unwrap();
+        assert_eq!(Some(FILE_HEADER_LEN), value);
     }
 
     #[test]
     pub fn test_insert() {
-        let path = Path::new("test_data/test_file");
-        if path.exists() {
-            fs::remove_file(path).expect("Failed to delete file");
-        }
-
         let key1 = b"vlad";
         let val1 = b"onis";
 
-        let akv = ActionKV::open(Path::new("test_data/test_file"));
+        let akv = ActionKV::with_storage(Box::new(MemoryStorage::new()), Options::default());
         assert!(akv.is_ok());
         let mut akv = akv.unwrap();
 
@@ -192,20 +698,26 @@ pub mod tests {
         assert!(res.is_ok());
 
         akv.f
-            .seek(SeekFrom::Start(0))
+            .seek(SeekFrom::Start(FILE_HEADER_LEN))
             .expect("Could not move cursor");
 
-        let record = ActionKV::process_record(&mut akv.f);
+        let record = ActionKV::process_record(&mut akv.f, akv.checksum, akv.limits);
         assert!(record.is_ok());
         let record = record.unwrap();
         assert_eq!(record.key, b"vlad");
         assert_eq!(record.value, b"onis");
     }
 
-    pub fn write_hardcoded_bitcask(path: &Path, key: &ByteStr, val: &ByteStr) -> io::Result<u8> {
+    pub fn write_hardcoded_bitcask<W: Write + Seek>(
+        f: &mut W,
+        key: &ByteStr,
+        val: &ByteStr,
+    ) -> io::Result<u8> {
         let mut to_write = vec![];
 
-        let mut f = open(path)?;
+        if f.seek(SeekFrom::End(0))? == 0 {
+            ActionKV::write_file_header(&mut to_write, ChecksumAlgorithm::Crc32)?;
+        }
 
         let key_len: u32 = key.len() as u32;
         let val_len: u32 = val.len() as u32;
@@ -215,9 +727,10 @@ pub mod tests {
             String::from_utf8_lossy(key),
             String::from_utf8_lossy(val)
         );
-        let check_sum = crc::crc32::checksum_ieee(data.as_bytes());
+        let check_sum = ChecksumAlgorithm::Crc32.checksum(DEFAULT_NAMESPACE, data.as_bytes());
 
         to_write.write_u32::<LittleEndian>(check_sum)?;
+        to_write.write_u32::<LittleEndian>(DEFAULT_NAMESPACE)?;
         to_write.write_u32::<LittleEndian>(key_len)?;
         to_write.write_u32::<LittleEndian>(val_len)?;
 
@@ -229,19 +742,8 @@ pub mod tests {
             to_write.write_u8(*byte)?;
         }
 
-        f.write(to_write.as_ref())?;
+        f.write_all(to_write.as_ref())?;
 
         Ok(0)
     }
-
-    fn open(path: &Path) -> io::Result<File> {
-        let f = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(path)?;
-
-        Ok(f)
-    }
 }
@@ -0,0 +1,168 @@
+use crate::{ActionKV, ByteStr};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Largest keys and values a store accepts. Writes beyond them are refused, and a record whose
+/// length fields claim more is treated as corrupt before anything is allocated for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_key_len: u32,
+    pub max_value_len: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_key_len: 64 << 10,
+            max_value_len: 256 << 20,
+        }
+    }
+}
+
+impl Limits {
+    /// Anything the record format can hold
+    pub const MAX: Limits = Limits {
+        max_key_len: u32::MAX,
+        max_value_len: u32::MAX,
+    };
+
+    fn check(&self, key_len: u64, val_len: u64) -> Result<(), LimitExceeded> {
+        if key_len > self.max_key_len as u64 {
+            return Err(LimitExceeded {
+                field: Field::Key,
+                len: key_len,
+                max: self.max_key_len,
+            });
+        }
+        if val_len > self.max_value_len as u64 {
+            return Err(LimitExceeded {
+                field: Field::Value,
+                len: val_len,
+                max: self.max_value_len,
+            });
+        }
+        Ok(())
+    }
+
+    /// Before a write, the error is `InvalidInput`
+    pub(crate) fn check_write(&self, key: &ByteStr, val: &ByteStr) -> io::Result<()> {
+        self.check(key.len() as u64, val.len() as u64)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// Lengths read from a record header, the error is `InvalidData`
+    pub(crate) fn check_read(&self, key_len: u32, val_len: u32) -> io::Result<()> {
+        self.check(key_len as u64, val_len as u64)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Key,
+    Value,
+}
+
+/// Carried inside the `io::Error` returned when a key or value is too long
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitExceeded {
+    pub field: Field,
+    pub len: u64,
+    pub max: u32,
+}
+
+impl LimitExceeded {
+    /// The limit that `e` ran into, if it was caused by one
+    pub fn from_io(e: &io::Error) -> Option<&LimitExceeded> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let field = match self.field {
+            Field::Key => "key",
+            Field::Value => "value",
+        };
+        write!(
+            f,
+            "{} of {} bytes exceeds the limit of {} bytes",
+            field, self.len, self.max
+        )
+    }
+}
+
+impl Error for LimitExceeded {}
+
+impl ActionKV {
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Field, LimitExceeded, Limits};
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::{ActionKV, Options};
+    use std::io;
+
+    fn limited(storage: &MemoryStorage) -> ActionKV {
+        let options = Options {
+            limits: Limits {
+                max_key_len: 4,
+                max_value_len: 8,
+            },
+            ..Options::default()
+        };
+        ActionKV::with_storage(Box::new(storage.clone()), options).unwrap()
+    }
+
+    #[test]
+    pub fn test_oversize_writes_are_refused() {
+        let storage = MemoryStorage::new();
+        let mut akv = limited(&storage);
+        akv.load().unwrap();
+        akv.insert(b"keys", b"12345678").unwrap();
+        let size = storage.size().unwrap();
+
+        let err = akv.insert(b"long key", b"1").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            LimitExceeded::from_io(&err),
+            Some(&LimitExceeded {
+                field: Field::Key,
+                len: 8,
+                max: 4,
+            })
+        );
+        let err = akv.insert_ignore_index(b"k", b"123456789").unwrap_err();
+        assert_eq!(LimitExceeded::from_io(&err).unwrap().field, Field::Value);
+        assert!(akv.delete(b"long key").is_err());
+
+        // Nothing of the refused writes reached the log
+        assert_eq!(storage.size().unwrap(), size);
+        assert_eq!(akv.get(b"keys").unwrap(), Some(b"12345678".to_vec()));
+    }
+
+    #[test]
+    pub fn test_oversize_records_are_rejected_on_read() {
+        let storage = MemoryStorage::new();
+        let mut akv =
+            ActionKV::with_storage(Box::new(storage.clone()), Options::default()).unwrap();
+        akv.insert(b"key", b"a value too long").unwrap();
+
+        let err = limited(&storage).load().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(LimitExceeded::from_io(&err).unwrap().len, 16);
+
+        // A length field of garbage must not turn into a 4 GiB allocation
+        let mut garbage = storage.contents();
+        garbage[8 + 12..8 + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+        let storage = MemoryStorage::from_bytes(garbage);
+        let mut akv = ActionKV::with_storage(Box::new(storage), Options::default()).unwrap();
+        let err = akv.load().unwrap_err();
+        assert_eq!(LimitExceeded::from_io(&err).unwrap().field, Field::Value);
+    }
+}
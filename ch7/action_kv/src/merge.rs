@@ -0,0 +1,382 @@
+//! Merge operators. `merge` appends only the operand, and the index points at it. Every operand
+//! record links back to the record the key held before, so reading the key walks the chain to
+//! the last full value and folds the operands onto it. Compaction writes the folded value, or
+//! copies the chain unchanged while its namespace has no operator. `merge` folds a chain on its
+//! own once it holds `MAX_CHAIN_OPERANDS` operands, so that reads stay short between
+//! compactions.
+
+use crate::index::Slot;
+use crate::transaction::TRANSACTION_NAMESPACE;
+use crate::{record_len, ActionKV, ByteStr, ByteString, NamespaceId, DEFAULT_NAMESPACE};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::{Seek, Write};
+
+/// Records of this namespace hold a merge operand. Their key is the merged key and their value
+/// is: namespace(u32), offset of the previous record of the key(u64, `NO_PREVIOUS` for none)
+/// and the operand
+pub(crate) const MERGE_NAMESPACE: NamespaceId = TRANSACTION_NAMESPACE - 1;
+const NO_PREVIOUS: u64 = u64::MAX;
+const OPERAND_HEADER_LEN: usize = 12;
+/// Longest chain `merge` builds before it writes a folded value instead of another operand
+pub(crate) const MAX_CHAIN_OPERANDS: u32 = 64;
+
+/// Folds operands, oldest first, onto the value a key held before them, `None` when it had none
+pub type MergeOperator =
+    Box<dyn Fn(&ByteStr, Option<&ByteStr>, &[ByteString]) -> ByteString + Send>;
+
+/// Operator of every namespace that has one
+#[derive(Default)]
+pub(crate) struct MergeOperators(HashMap<NamespaceId, MergeOperator>);
+
+impl MergeOperators {
+    pub(crate) fn contains(&self, namespace: NamespaceId) -> bool {
+        self.0.contains_key(&namespace)
+    }
+}
+
+impl fmt::Debug for MergeOperators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Operand {
+    pub(crate) namespace: NamespaceId,
+    pub(crate) previous: Option<u64>,
+    pub(crate) operand: ByteString,
+}
+
+impl Operand {
+    fn encode(&self) -> ByteString {
+        let mut value = Vec::with_capacity(OPERAND_HEADER_LEN + self.operand.len());
+        value.write_u32::<LittleEndian>(self.namespace).unwrap();
+        value
+            .write_u64::<LittleEndian>(self.previous.unwrap_or(NO_PREVIOUS))
+            .unwrap();
+        value.extend_from_slice(&self.operand);
+        value
+    }
+
+    pub(crate) fn decode(value: &ByteStr) -> io::Result<Operand> {
+        if value.len() < OPERAND_HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed merge operand record",
+            ));
+        }
+        let mut header = &value[..OPERAND_HEADER_LEN];
+        let namespace = header.read_u32::<LittleEndian>()?;
+        let previous = header.read_u64::<LittleEndian>()?;
+        Ok(Operand {
+            namespace,
+            previous: Some(previous).filter(|previous| *previous != NO_PREVIOUS),
+            operand: value[OPERAND_HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+impl ActionKV {
+    pub fn register_merge_operator<F>(&mut self, operator: F)
+    where
+        F: Fn(&ByteStr, Option<&ByteStr>, &[ByteString]) -> ByteString + Send + 'static,
+    {
+        self.register_merge_operator_in(DEFAULT_NAMESPACE, operator)
+    }
+
+    /// Operators are not recorded in the log. A store holding operands has to get its operator
+    /// registered again before the merged keys can be read, compaction leaves them unfolded
+    /// until then
+    pub fn register_merge_operator_in<F>(&mut self, namespace: NamespaceId, operator: F)
+    where
+        F: Fn(&ByteStr, Option<&ByteStr>, &[ByteString]) -> ByteString + Send + 'static,
+    {
+        self.merge_operators.0.insert(namespace, Box::new(operator));
+    }
+
+    pub fn merge(&mut self, key: &ByteStr, operand: &ByteStr) -> io::Result<()> {
+        self.merge_in(DEFAULT_NAMESPACE, key, operand)
+    }
+
+    /// Records `operand` for `key` without reading the value it applies to. The namespace needs
+    /// a merge operator
+    pub fn merge_in(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        operand: &ByteStr,
+    ) -> io::Result<()> {
+        let previous = self.namespace_index(namespace)?.get_slot(key)?;
+        if !self.merge_operators.contains(namespace) {
+            return Err(no_operator(namespace));
+        }
+        if let Some(previous) = previous.filter(|slot| slot.operands >= MAX_CHAIN_OPERANDS) {
+            let base = self.value_at(namespace, previous.position)?;
+            let operator = &self.merge_operators.0[&namespace];
+            let value = operator(key, Some(&base), &[operand.to_vec()]);
+            return self.insert_in(namespace, key, &value);
+        }
+
+        let value = Operand {
+            namespace,
+            previous: previous.map(|slot| slot.position),
+            operand: operand.to_vec(),
+        }
+        .encode();
+        let position = self.append(MERGE_NAMESPACE, key, &value)?;
+
+        // The records before it stay live, they are part of the value until it is folded
+        let len = record_len(key.len(), value.len());
+        self.counters.live_bytes += len;
+        let slot = Slot {
+            position,
+            len: len + previous.map_or(0, |slot| slot.len),
+            operands: previous.map_or(0, |slot| slot.operands) + 1,
+        };
+        self.namespace_index_mut(namespace)?
+            .insert(key.to_vec(), slot)?;
+        self.cache.remove(namespace, key);
+        self.metrics.merged();
+        self.bloom_insert(namespace, key)?;
+        self.notify_watchers_of_merge(position, namespace, key, operand, value.len());
+
+        // Only the folded value can keep these up to date
+        if self
+            .secondary
+            .values()
+            .any(|index| index.namespace() == namespace)
+            || self.merkle.contains_key(&namespace)
+        {
+            let value = self.value_at(namespace, position)?;
+            self.update_secondary_indexes(namespace, key, Some(&value));
+            self.update_merkle_trees(namespace, key, Some(&value));
+        }
+
+        self.maybe_compact();
+        Ok(())
+    }
+
+    /// Value of the record at `position`, with the operands folded in when it is one
+    pub(crate) fn value_at(
+        &mut self,
+        namespace: NamespaceId,
+        position: u64,
+    ) -> io::Result<ByteString> {
+        let mut key = None;
+        let mut operands = Vec::new();
+        let mut base = None;
+        let mut next = Some(position);
+        while let Some(position) = next {
+            let kv = self.get_at(position)?;
+            if kv.namespace != MERGE_NAMESPACE {
+                base = Some(kv.value);
+                break;
+            }
+            let operand = Operand::decode(&kv.value)?;
+            key.get_or_insert(kv.key);
+            operands.push(operand.operand);
+            next = operand.previous;
+        }
+
+        let key = match key {
+            // A plain record, nothing to fold
+            None => return Ok(base.unwrap_or_default()),
+            Some(key) => key,
+        };
+        operands.reverse();
+
+        let operator = self
+            .merge_operators
+            .0
+            .get(&namespace)
+            .ok_or_else(|| no_operator(namespace))?;
+        Ok(operator(&key, base.as_deref(), &operands))
+    }
+
+    /// Writes the records of the chain ending at `position` to `out`, oldest first, every
+    /// operand linked to the new offset of the record before it. Returns the slot of the copy
+    /// and how many records it took
+    pub(crate) fn copy_chain<W: Write + Seek>(
+        &mut self,
+        out: &mut W,
+        position: u64,
+    ) -> io::Result<(Slot, u64)> {
+        let mut chain = Vec::new();
+        let mut next = Some(position);
+        while let Some(position) = next {
+            let kv = self.get_at(position)?;
+            next = None;
+            if kv.namespace == MERGE_NAMESPACE {
+                next = Operand::decode(&kv.value)?.previous;
+            }
+            chain.push(kv);
+        }
+
+        let records = chain.len() as u64;
+        let mut copied: Option<Slot> = None;
+        for kv in chain.into_iter().rev() {
+            let (value, operands) = if kv.namespace == MERGE_NAMESPACE {
+                let mut operand = Operand::decode(&kv.value)?;
+                operand.previous = copied.map(|slot| slot.position);
+                (operand.encode(), copied.map_or(0, |slot| slot.operands) + 1)
+            } else {
+                (kv.value, 0)
+            };
+            let position =
+                ActionKV::write_record(out, self.checksum, kv.namespace, &kv.key, &value)?;
+            copied = Some(Slot {
+                position,
+                len: copied.map_or(0, |slot| slot.len) + record_len(kv.key.len(), value.len()),
+                operands,
+            });
+        }
+
+        Ok((copied.expect("a chain holds at least one record"), records))
+    }
+}
+
+fn no_operator(namespace: NamespaceId) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no merge operator registered for namespace {}", namespace),
+    )
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::MAX_CHAIN_OPERANDS;
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+    use crate::watch::ChangeKind;
+    use crate::{ActionKV, ByteStr, ByteString, DEFAULT_NAMESPACE};
+
+    /// Comma separated list, operands are appended to it
+    fn list(_key: &ByteStr, base: Option<&ByteStr>, operands: &[ByteString]) -> ByteString {
+        let mut items: Vec<&[u8]> = base.into_iter().collect();
+        items.extend(operands.iter().map(|operand| operand.as_slice()));
+        items.join(&b","[..])
+    }
+
+    fn open_with_operator(storage: &MemoryStorage) -> ActionKV {
+        let mut akv = open_memory(storage);
+        akv.register_merge_operator(list);
+        akv
+    }
+
+    #[test]
+    pub fn test_merge_folds_lazily() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        assert!(akv.merge(b"fruit", b"apple").is_err());
+        akv.register_merge_operator(list);
+        let events = akv.watch(b"fruit");
+
+        akv.merge(b"fruit", b"apple").unwrap();
+        akv.merge(b"fruit", b"pear").unwrap();
+        assert_eq!(akv.get(b"fruit").unwrap(), Some(b"apple,pear".to_vec()));
+        assert_eq!(
+            events
+                .try_iter()
+                .map(|event| event.kind)
+                .collect::<Vec<_>>(),
+            vec![
+                ChangeKind::Merge(b"apple".to_vec()),
+                ChangeKind::Merge(b"pear".to_vec())
+            ]
+        );
+
+        // A put starts the chain over
+        akv.insert(b"veg", b"leek").unwrap();
+        akv.merge(b"veg", b"kale").unwrap();
+        akv.insert(b"fruit", b"plum").unwrap();
+        akv.merge(b"fruit", b"fig").unwrap();
+        assert_eq!(akv.get(b"fruit").unwrap(), Some(b"plum,fig".to_vec()));
+        let stats = akv.stats();
+        assert_eq!(stats.live_keys, 2);
+
+        // Without its operator a merged key cannot be read
+        let mut reopened = open_memory(&storage);
+        assert!(reopened.get(b"veg").is_err());
+        assert_eq!(reopened.stats().dead_bytes, stats.dead_bytes);
+
+        let mut akv = open_with_operator(&storage);
+        assert_eq!(akv.get(b"veg").unwrap(), Some(b"leek,kale".to_vec()));
+        akv.delete(b"veg").unwrap();
+        akv.merge(b"veg", b"leek").unwrap();
+        akv.compact().unwrap();
+        assert_eq!(akv.stats().dead_bytes, 0);
+
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.get(b"fruit").unwrap(), Some(b"plum,fig".to_vec()));
+        assert_eq!(akv.get(b"veg").unwrap(), Some(b"leek".to_vec()));
+    }
+
+    #[test]
+    pub fn test_merge_keeps_live_bytes_and_transactions() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_with_operator(&storage);
+        akv.insert(b"list", b"a").unwrap();
+        let mut tx = akv.begin();
+        assert_eq!(tx.get(&mut akv, b"list").unwrap(), Some(b"a".to_vec()));
+        akv.merge(b"list", b"b").unwrap();
+        tx.insert(b"list", b"x");
+        // The merge moved the key, the transaction read an older version
+        assert!(akv.commit(tx).is_err());
+
+        let mut tx = akv.begin();
+        assert_eq!(tx.get(&mut akv, b"list").unwrap(), Some(b"a,b".to_vec()));
+        tx.insert(b"list", b"x");
+        akv.commit(tx).unwrap();
+
+        let dead = akv.stats().dead_bytes;
+        let mut akv = open_with_operator(&storage);
+        assert_eq!(akv.stats().dead_bytes, dead);
+        assert_eq!(akv.get(b"list").unwrap(), Some(b"x".to_vec()));
+    }
+
+    #[test]
+    pub fn test_chains_survive_compaction_and_stay_short() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_with_operator(&storage);
+        akv.insert(b"fruit", b"plum").unwrap();
+        akv.merge(b"fruit", b"fig").unwrap();
+        akv.merge(b"fruit", b"kiwi").unwrap();
+        akv.insert(b"veg", b"leek").unwrap();
+        akv.insert(b"veg", b"kale").unwrap();
+
+        // Without its operator the chain is copied, not folded
+        let mut akv = open_memory(&storage);
+        akv.compact().unwrap();
+        let stats = akv.stats();
+        assert_eq!(stats.total_records, 4);
+        assert_eq!(stats.dead_bytes, 0);
+        let mut akv = open_with_operator(&storage);
+        assert_eq!(akv.stats().dead_bytes, 0);
+        assert_eq!(akv.stats().file_size, stats.file_size);
+        assert_eq!(akv.get(b"fruit").unwrap(), Some(b"plum,fig,kiwi".to_vec()));
+        assert_eq!(akv.get(b"veg").unwrap(), Some(b"kale".to_vec()));
+
+        for i in 0..MAX_CHAIN_OPERANDS * 2 {
+            akv.merge(b"count", i.to_string().as_bytes()).unwrap();
+        }
+        let expected = (0..MAX_CHAIN_OPERANDS * 2)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+            .into_bytes();
+        let slot = akv.index[&DEFAULT_NAMESPACE]
+            .get_slot(b"count")
+            .unwrap()
+            .unwrap();
+        assert_eq!(slot.operands, MAX_CHAIN_OPERANDS - 1);
+        assert_eq!(akv.get(b"count").unwrap(), Some(expected.clone()));
+
+        let mut akv = open_with_operator(&storage);
+        let reloaded = akv.index[&DEFAULT_NAMESPACE].get_slot(b"count").unwrap();
+        assert_eq!(reloaded, Some(slot));
+        assert_eq!(akv.get(b"count").unwrap(), Some(expected));
+    }
+}
@@ -0,0 +1,301 @@
+//! Merkle tree over the keys of a namespace. Two replicas holding the same data have the same
+//! root, and where they differ, comparing subtrees narrows it down to a few leaves without
+//! looking at anything else. `sync` builds the anti-entropy protocol on top of it.
+
+use crate::{ActionKV, ByteStr, ByteString, NamespaceId, DEFAULT_NAMESPACE};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use xxhash_rust::xxh64::xxh64;
+
+/// Deep enough for a million leaves, more would mostly hold empty buckets
+pub const MAX_DEPTH: u32 = 20;
+
+/// Node ids follow the heap layout: the root is 1, node `n` has the children `2n` and `2n + 1`
+/// and the leaves are the nodes from `1 << depth` on.
+///
+/// The tree keeps a copy of every key of its namespace, with a hash of its value, so that
+/// `bucket` can answer without touching the log. That is the length of the key plus about 40
+/// bytes of hash and map overhead per key, on top of the index, and 40 bytes per leaf. Enable
+/// it on namespaces whose keys fit in memory a second time
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    depth: u32,
+    /// Hash of the value of every key, grouped by the leaf the key falls in
+    buckets: Vec<BTreeMap<ByteString, u64>>,
+    nodes: Vec<u64>,
+    /// Leaves changed since their hashes were last computed
+    dirty: BTreeSet<usize>,
+}
+
+pub fn value_hash(value: &ByteStr) -> u64 {
+    xxh64(value, 0)
+}
+
+fn combine(left: u64, right: u64) -> u64 {
+    // Keeps empty subtrees at 0, so an empty tree has the same root whatever its depth
+    if left == 0 && right == 0 {
+        return 0;
+    }
+    let mut buf = [0; 16];
+    buf[..8].copy_from_slice(&left.to_le_bytes());
+    buf[8..].copy_from_slice(&right.to_le_bytes());
+    xxh64(&buf, 0)
+}
+
+impl MerkleTree {
+    pub fn new(depth: u32) -> io::Result<MerkleTree> {
+        if depth > MAX_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("a merkle tree is at most {} levels deep", MAX_DEPTH),
+            ));
+        }
+
+        let leaves = 1 << depth;
+        Ok(MerkleTree {
+            depth,
+            buckets: vec![BTreeMap::new(); leaves],
+            nodes: vec![0; leaves * 2],
+            dirty: BTreeSet::new(),
+        })
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn leaves(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Keys in the tree
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|bucket| bucket.is_empty())
+    }
+
+    /// Leaf `key` falls in, taken from the top bits of its hash so that the split between two
+    /// subtrees is the same on every replica
+    pub fn leaf_of(&self, key: &ByteStr) -> usize {
+        match self.depth {
+            0 => 0,
+            depth => (xxh64(key, 0) >> (64 - depth)) as usize,
+        }
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) {
+        let leaf = self.leaf_of(key);
+        self.buckets[leaf].insert(key.to_vec(), value_hash(value));
+        self.dirty.insert(leaf);
+    }
+
+    pub fn remove(&mut self, key: &ByteStr) {
+        let leaf = self.leaf_of(key);
+        if self.buckets[leaf].remove(key).is_some() {
+            self.dirty.insert(leaf);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for bucket in &mut self.buckets {
+            bucket.clear();
+        }
+        self.nodes.iter_mut().for_each(|node| *node = 0);
+        self.dirty.clear();
+    }
+
+    /// Keys of one leaf with the hashes of their values, sorted by key
+    pub fn bucket(&self, leaf: usize) -> &BTreeMap<ByteString, u64> {
+        &self.buckets[leaf]
+    }
+
+    pub fn root(&mut self) -> u64 {
+        self.node(1)
+    }
+
+    /// Hash of the subtree below node `id`, 0 for an empty one
+    pub fn node(&mut self, id: usize) -> u64 {
+        self.refresh();
+        self.nodes[id]
+    }
+
+    fn refresh(&mut self) {
+        let dirty = std::mem::take(&mut self.dirty);
+        let first_leaf = self.buckets.len();
+
+        let mut parents = BTreeSet::new();
+        for leaf in dirty {
+            self.nodes[first_leaf + leaf] = leaf_hash(&self.buckets[leaf]);
+            parents.insert((first_leaf + leaf) / 2);
+        }
+        // One level at a time, every parent after both its children
+        while !parents.is_empty() {
+            let mut next = BTreeSet::new();
+            for node in parents.into_iter().filter(|node| *node > 0) {
+                self.nodes[node] = combine(self.nodes[node * 2], self.nodes[node * 2 + 1]);
+                next.insert(node / 2);
+            }
+            parents = next;
+        }
+    }
+}
+
+fn leaf_hash(bucket: &BTreeMap<ByteString, u64>) -> u64 {
+    if bucket.is_empty() {
+        return 0;
+    }
+    let mut buf = Vec::new();
+    for (key, hash) in bucket {
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&hash.to_le_bytes());
+    }
+    xxh64(&buf, 0)
+}
+
+impl ActionKV {
+    pub fn enable_merkle_tree(&mut self, depth: u32) -> io::Result<()> {
+        self.enable_merkle_tree_in(DEFAULT_NAMESPACE, depth)
+    }
+
+    /// Starts keeping a tree over `namespace`, built from what the namespace holds now and
+    /// kept up to date by every write after that. Building it reads the value of every key, as
+    /// does every `load` while it is enabled
+    pub fn enable_merkle_tree_in(&mut self, namespace: NamespaceId, depth: u32) -> io::Result<()> {
+        self.namespace_index(namespace)?;
+        let mut tree = MerkleTree::new(depth)?;
+        self.fill_merkle_tree(namespace, &mut tree)?;
+        self.merkle.insert(namespace, tree);
+        Ok(())
+    }
+
+    pub fn disable_merkle_tree_in(&mut self, namespace: NamespaceId) -> bool {
+        self.merkle.remove(&namespace).is_some()
+    }
+
+    pub fn merkle_tree(&mut self) -> Option<&mut MerkleTree> {
+        self.merkle_tree_in(DEFAULT_NAMESPACE)
+    }
+
+    pub fn merkle_tree_in(&mut self, namespace: NamespaceId) -> Option<&mut MerkleTree> {
+        self.merkle.get_mut(&namespace)
+    }
+
+    /// `value` is `None` when the key was deleted
+    pub(crate) fn update_merkle_trees(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        value: Option<&ByteStr>,
+    ) {
+        if let Some(tree) = self.merkle.get_mut(&namespace) {
+            match value {
+                Some(value) => tree.insert(key, value),
+                None => tree.remove(key),
+            }
+        }
+    }
+
+    /// Trees of namespaces that no longer exist are dropped
+    pub(crate) fn rebuild_merkle_trees(&mut self) -> io::Result<()> {
+        let mut trees = std::mem::take(&mut self.merkle);
+        trees.retain(|namespace, _| self.index.contains_key(namespace));
+
+        let mut result = Ok(());
+        for (namespace, tree) in trees.iter_mut() {
+            tree.clear();
+            if result.is_ok() {
+                result = self.fill_merkle_tree(*namespace, tree);
+            }
+        }
+
+        self.merkle = trees;
+        result
+    }
+
+    fn fill_merkle_tree(
+        &mut self,
+        namespace: NamespaceId,
+        tree: &mut MerkleTree,
+    ) -> io::Result<()> {
+        // The tree ends up with every key anyway, sorting them first turns the reads into one
+        // pass over the log
+        let mut entries = self
+            .namespace_index(namespace)?
+            .entries()
+            .map(|entry| entry.map(|(key, position)| (position, key)))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_unstable();
+
+        for (position, key) in entries {
+            let value = self.value_at(namespace, position)?;
+            tree.insert(&key, &value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::MerkleTree;
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    pub fn test_tree_follows_contents() {
+        let mut a = MerkleTree::new(4).unwrap();
+        let mut b = MerkleTree::new(4).unwrap();
+        assert_eq!(a.root(), 0);
+        assert!(MerkleTree::new(21).is_err());
+
+        for i in 0..100u32 {
+            a.insert(&i.to_le_bytes(), b"value");
+        }
+        // Order of the writes does not matter, only what ends up in the tree
+        for i in (0..100u32).rev() {
+            b.insert(&i.to_le_bytes(), b"value");
+        }
+        assert_eq!(a.root(), b.root());
+        assert_ne!(a.root(), 0);
+        assert_eq!(a.len(), 100);
+
+        b.insert(&7u32.to_le_bytes(), b"changed");
+        assert_ne!(a.root(), b.root());
+        let leaf = a.leaf_of(&7u32.to_le_bytes());
+        let differing: Vec<usize> = (0..a.leaves())
+            .filter(|leaf| a.node(16 + leaf) != b.node(16 + leaf))
+            .collect();
+        assert_eq!(differing, vec![leaf]);
+
+        b.insert(&7u32.to_le_bytes(), b"value");
+        b.insert(b"extra", b"value");
+        b.remove(b"extra");
+        assert_eq!(a.root(), b.root());
+    }
+
+    #[test]
+    pub fn test_store_keeps_tree_up_to_date() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        akv.insert(b"a", b"1").unwrap();
+        akv.enable_merkle_tree(6).unwrap();
+        akv.insert(b"b", b"2").unwrap();
+        akv.insert(b"c", b"3").unwrap();
+        akv.delete(b"c").unwrap();
+
+        let mut expected = MerkleTree::new(6).unwrap();
+        expected.insert(b"a", b"1");
+        expected.insert(b"b", b"2");
+        let root = akv.merkle_tree().unwrap().root();
+        assert_eq!(root, expected.root());
+
+        akv.compact().unwrap();
+        akv.load().unwrap();
+        assert_eq!(akv.merkle_tree().unwrap().root(), root);
+        assert!(akv.disable_merkle_tree_in(0));
+        assert!(akv.merkle_tree().is_none());
+    }
+}
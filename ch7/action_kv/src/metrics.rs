@@ -0,0 +1,359 @@
+//! Operation metrics in the Prometheus text exposition format. The counters live behind an
+//! `Arc`, so a `Metrics` taken from a store before it moves into an `AsyncHandle` or another
+//! thread keeps following it, and `serve` can answer scrapes from a thread of its own.
+
+use crate::ActionKV;
+use std::fmt::Write as _;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds in seconds, compactions rewrite the whole log
+const COMPACTION_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+const FSYNC_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// How long `respond` waits on a client to send its request or take the answer. Connections
+/// are answered one at a time, so a stalled client holds up the rest until then
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// One per bound, then one for everything above the last
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut total = 0;
+        let cumulative = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                total += bucket.load(Ordering::Relaxed);
+                total
+            })
+            .collect();
+        HistogramSnapshot {
+            bounds: self.bounds.to_vec(),
+            cumulative,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)).as_secs_f64(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Registry {
+    gets: AtomicU64,
+    inserts: AtomicU64,
+    deletes: AtomicU64,
+    merges: AtomicU64,
+    bytes_written: AtomicU64,
+    checksum_failures: AtomicU64,
+    compaction: Histogram,
+    fsync: Histogram,
+}
+
+/// Handle on the metrics of one store, clones share them
+#[derive(Debug, Clone)]
+pub struct Metrics(Arc<Registry>);
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics(Arc::new(Registry {
+            gets: AtomicU64::new(0),
+            inserts: AtomicU64::new(0),
+            deletes: AtomicU64::new(0),
+            merges: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            checksum_failures: AtomicU64::new(0),
+            compaction: Histogram::new(COMPACTION_BUCKETS),
+            fsync: Histogram::new(FSYNC_BUCKETS),
+        }))
+    }
+}
+
+impl Metrics {
+    pub(crate) fn got(&self) {
+        self.0.gets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inserted(&self) {
+        self.0.inserts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn deleted(&self) {
+        self.0.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn merged(&self) {
+        self.0.merges.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn wrote(&self, bytes: u64) {
+        self.0.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts `e` when a record failed its checksum
+    pub(crate) fn read_failed(&self, e: &io::Error) {
+        if crate::checksum::ChecksumMismatch::from_io(e).is_some() {
+            self.0.checksum_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn compacted(&self, elapsed: Duration) {
+        self.0.compaction.observe(elapsed);
+    }
+
+    pub(crate) fn synced(&self, elapsed: Duration) {
+        self.0.fsync.observe(elapsed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            gets: self.0.gets.load(Ordering::Relaxed),
+            inserts: self.0.inserts.load(Ordering::Relaxed),
+            deletes: self.0.deletes.load(Ordering::Relaxed),
+            merges: self.0.merges.load(Ordering::Relaxed),
+            bytes_written: self.0.bytes_written.load(Ordering::Relaxed),
+            checksum_failures: self.0.checksum_failures.load(Ordering::Relaxed),
+            compaction: self.0.compaction.snapshot(),
+            fsync: self.0.fsync.snapshot(),
+        }
+    }
+
+    /// Current values in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        self.snapshot().render()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Upper bounds of the buckets in seconds
+    pub bounds: Vec<f64>,
+    /// Observations at or below each bound, then all of them
+    pub cumulative: Vec<u64>,
+    /// Seconds taken by all the observations together
+    pub sum: f64,
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> u64 {
+        self.cumulative.last().copied().unwrap_or(0)
+    }
+}
+
+/// Values of every metric at one point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub gets: u64,
+    /// Every key written by `insert`, a transaction or a counter
+    pub inserts: u64,
+    pub deletes: u64,
+    pub merges: u64,
+    /// Bytes appended to the log or written out by compaction
+    pub bytes_written: u64,
+    pub checksum_failures: u64,
+    pub compaction: HistogramSnapshot,
+    pub fsync: HistogramSnapshot,
+}
+
+impl Snapshot {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("akv_gets_total", "Keys read", self.gets),
+            ("akv_inserts_total", "Keys written", self.inserts),
+            ("akv_deletes_total", "Keys deleted", self.deletes),
+            ("akv_merges_total", "Merge operands written", self.merges),
+            (
+                "akv_written_bytes_total",
+                "Bytes written to the log, compaction included",
+                self.bytes_written,
+            ),
+            (
+                "akv_checksum_failures_total",
+                "Records read back with a bad checksum",
+                self.checksum_failures,
+            ),
+        ];
+        for (name, help, value) in counters {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+
+        let histograms = [
+            (
+                "akv_compaction_duration_seconds",
+                "Time taken by compactions",
+                &self.compaction,
+            ),
+            (
+                "akv_fsync_duration_seconds",
+                "Time taken to make the log durable",
+                &self.fsync,
+            ),
+        ];
+        for (name, help, histogram) in histograms {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} histogram", name).unwrap();
+            for (bound, count) in histogram.bounds.iter().zip(&histogram.cumulative) {
+                writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+            }
+            writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count()).unwrap();
+            writeln!(out, "{}_sum {}", name, histogram.sum).unwrap();
+            writeln!(out, "{}_count {}", name, histogram.count()).unwrap();
+        }
+        out
+    }
+}
+
+impl ActionKV {
+    /// Shares the metrics of this store, they keep counting for as long as it is open
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+}
+
+/// Answers one HTTP request, `GET /metrics` gets the metrics and anything else a 404
+pub fn respond(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Headers are of no interest, but have to be read before answering
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "not found, try /metrics\n".to_string()),
+    };
+
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Serves the metrics over HTTP until accepting a connection fails. A client that goes away
+/// or stalls in the middle of a request only loses its own answer
+pub fn serve(listener: TcpListener, metrics: Metrics) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let _ = respond(stream?, &metrics);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::serve;
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    pub fn test_operations_are_counted() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        let metrics = akv.metrics();
+        akv.insert(b"a", b"1").unwrap();
+        akv.insert(b"b", b"2").unwrap();
+        akv.delete(b"b").unwrap();
+        akv.get(b"a").unwrap();
+        akv.get(b"b").unwrap();
+        akv.sync().unwrap();
+        akv.compact().unwrap();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.gets, 2);
+        assert_eq!(snapshot.inserts, 2);
+        assert_eq!(snapshot.deletes, 1);
+        // Two records of 18 bytes, a tombstone of 17 and 8 + 18 for the compacted log
+        assert_eq!(snapshot.bytes_written, 2 * 18 + 17 + 26);
+        assert_eq!(snapshot.compaction.count(), 1);
+        // The explicit one and the one of the compacted log
+        assert_eq!(snapshot.fsync.count(), 2);
+
+        // Corrupt the value of the only record left
+        let mut raw = storage.clone();
+        raw.seek(SeekFrom::Start(25)).unwrap();
+        raw.write_all(b"X").unwrap();
+        assert!(akv.get(b"a").is_err());
+        assert_eq!(metrics.snapshot().checksum_failures, 1);
+    }
+
+    #[test]
+    pub fn test_serves_prometheus_text() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        akv.insert(b"a", b"1").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = akv.metrics();
+        thread::spawn(move || serve(listener, metrics));
+        // Connects and never sends its request
+        let _stalled = TcpStream::connect(addr).unwrap();
+
+        let scrape = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = scrape("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE akv_inserts_total counter\nakv_inserts_total 1\n"));
+        assert!(response.contains("akv_fsync_duration_seconds_bucket{le=\"+Inf\"} 0\n"));
+        assert!(response.contains("akv_compaction_duration_seconds_count 0\n"));
+
+        akv.get(b"a").unwrap();
+        assert!(scrape("/metrics").contains("akv_gets_total 1\n"));
+        assert!(scrape("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
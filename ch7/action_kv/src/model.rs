@@ -0,0 +1,207 @@
+//! Randomised tests against a `HashMap` oracle, plus a crash simulator that cuts the log at
+//! every byte offset and checks that recovery always lands on a prefix of the committed writes.
+
+use crate::storage::{MemoryStorage, Storage};
+use crate::{ActionKV, ByteString, Options};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+type Oracle = HashMap<ByteString, ByteString>;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Insert(u8, ByteString),
+    Delete(u8),
+    /// Writes of one transaction, `None` deletes
+    Commit(Vec<(u8, Option<ByteString>)>),
+    Compact,
+    Reopen,
+}
+
+/// Few distinct keys, so that overwrites and deletes of existing keys are common
+const KEYS: u8 = 8;
+
+fn key(k: u8) -> ByteString {
+    format!("key-{}", k).into_bytes()
+}
+
+fn value() -> impl Strategy<Value = ByteString> {
+    vec(any::<u8>(), 1..16)
+}
+
+fn write_op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..KEYS, value()).prop_map(|(k, v)| Op::Insert(k, v)),
+        2 => (0..KEYS).prop_map(Op::Delete),
+        1 => vec((0..KEYS, proptest::option::of(value())), 1..4).prop_map(Op::Commit),
+    ]
+}
+
+fn any_op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        8 => write_op(),
+        1 => Just(Op::Compact),
+        1 => Just(Op::Reopen),
+    ]
+}
+
+fn open(storage: &MemoryStorage, options: &Options) -> ActionKV {
+    let mut akv = ActionKV::with_storage(Box::new(storage.clone()), options.clone()).unwrap();
+    akv.load().unwrap();
+    akv
+}
+
+fn apply(akv: &mut ActionKV, oracle: &mut Oracle, op: &Op) {
+    match op {
+        Op::Insert(k, v) => {
+            akv.insert(&key(*k), v).unwrap();
+            oracle.insert(key(*k), v.clone());
+        }
+        Op::Delete(k) => {
+            akv.delete(&key(*k)).unwrap();
+            oracle.remove(&key(*k));
+        }
+        Op::Commit(writes) => {
+            let mut tx = akv.begin();
+            for (k, v) in writes {
+                match v {
+                    Some(v) => tx.insert(&key(*k), v),
+                    None => tx.delete(&key(*k)),
+                }
+            }
+            akv.commit(tx).unwrap();
+
+            // Later writes of the same key win, like they do inside the transaction
+            for (k, v) in writes {
+                match v {
+                    Some(v) => oracle.insert(key(*k), v.clone()),
+                    None => oracle.remove(&key(*k)),
+                };
+            }
+        }
+        Op::Compact | Op::Reopen => unreachable!("handled by the caller"),
+    }
+}
+
+fn check(akv: &mut ActionKV, oracle: &Oracle) {
+    for k in 0..KEYS {
+        assert_eq!(akv.get(&key(k)).unwrap(), oracle.get(&key(k)).cloned());
+        assert_eq!(
+            akv.contains_key(&key(k)).unwrap(),
+            oracle.contains_key(&key(k))
+        );
+    }
+
+    let mut keys: Vec<ByteString> = akv.keys().map(|key| key.unwrap()).collect();
+    keys.sort();
+    let expected: BTreeMap<_, _> = oracle.iter().collect();
+    assert_eq!(keys, expected.keys().cloned().cloned().collect::<Vec<_>>());
+    assert_eq!(akv.stats().live_keys, oracle.len());
+}
+
+fn run_model(ops: &[Op], options: &Options) {
+    let storage = MemoryStorage::new();
+    let mut akv = open(&storage, options);
+    let mut oracle = Oracle::new();
+
+    for op in ops {
+        match op {
+            Op::Compact => akv.compact().unwrap(),
+            Op::Reopen => {
+                drop(akv);
+                akv = open(&storage, options);
+            }
+            op => apply(&mut akv, &mut oracle, op),
+        }
+        check(&mut akv, &oracle);
+    }
+
+    // Whatever happened in between, the log replays to the same state
+    drop(akv);
+    check(&mut open(&storage, options), &oracle);
+}
+
+/// Writes `ops`, then loads every prefix of the resulting log as if the process had died after
+/// writing that many bytes
+fn check_every_crash_point(ops: &[Op]) {
+    let storage = MemoryStorage::new();
+    let mut akv = open(&storage, &Options::default());
+    let mut oracle = Oracle::new();
+
+    // Log length after every committed write, with the state it stands for
+    let mut committed = vec![(storage.size().unwrap(), oracle.clone())];
+    for op in ops {
+        apply(&mut akv, &mut oracle, op);
+        committed.push((storage.size().unwrap(), oracle.clone()));
+    }
+    drop(akv);
+    let log = storage.contents();
+
+    for cut in committed[0].0..=log.len() as u64 {
+        let crashed = MemoryStorage::from_bytes(log[..cut as usize].to_vec());
+        let mut akv = open(&crashed, &Options::default());
+
+        let (len, state) = committed.iter().rev().find(|(len, _)| *len <= cut).unwrap();
+        check(&mut akv, state);
+        assert_eq!(
+            crashed.size().unwrap(),
+            *len,
+            "torn tail left after a cut at {}",
+            cut
+        );
+    }
+}
+
+pub mod tests {
+    use super::{any_op, check_every_crash_point, run_model, write_op, Op};
+    use crate::storage::tests::temp_dir;
+    use crate::{IndexMode, Options};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_model(ops in vec(any_op(), 1..60)) {
+            run_model(&ops, &Options::default());
+        }
+
+        #[test]
+        fn test_model_spilled_and_cached(ops in vec(any_op(), 1..60)) {
+            let dir = temp_dir();
+            let options = Options {
+                value_cache: 48,
+                index: IndexMode::Spill {
+                    dir: dir.path().to_path_buf(),
+                    memory_entries: 3,
+                },
+                ..Options::default()
+            };
+            run_model(&ops, &options);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn test_random_crashes(ops in vec(write_op(), 1..12)) {
+            check_every_crash_point(&ops);
+        }
+    }
+
+    #[test]
+    pub fn test_every_crash_point() {
+        check_every_crash_point(&[
+            Op::Insert(0, b"first".to_vec()),
+            Op::Insert(1, b"second".to_vec()),
+            Op::Commit(vec![
+                (0, None),
+                (2, Some(b"third".to_vec())),
+                (1, Some(b"fourth".to_vec())),
+            ]),
+            Op::Delete(1),
+            Op::Insert(0, b"fifth".to_vec()),
+        ]);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::merge::MERGE_NAMESPACE;
+use crate::{record_len, ActionKV, Index, IndexConfig, KeyValuePair};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+
+/// Every record carries the id of the namespace it belongs to, each namespace has its own index
+pub type NamespaceId = u32;
+
+/// Namespace used by the plain `get`/`insert`/`delete` calls, it always exists
+pub const DEFAULT_NAMESPACE: NamespaceId = 0;
+pub const DEFAULT_NAMESPACE_NAME: &str = "default";
+
+/// Reserved namespace holding the catalog: one record per namespace, the key is the name and
+/// the value is the id as a little endian u32. A tombstone in the catalog drops the namespace
+pub(crate) const CATALOG_NAMESPACE: NamespaceId = u32::MAX;
+
+/// Size of the catalog record that keeps a namespace alive
+pub(crate) fn catalog_record_len(name: &str) -> u64 {
+    record_len(name.len(), std::mem::size_of::<NamespaceId>())
+}
+
+pub(crate) fn unknown(namespace: NamespaceId) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("unknown namespace {}", namespace),
+    )
+}
+
+/// Replays one catalog record while loading the log
+pub(crate) fn apply_catalog_record(
+    namespaces: &mut HashMap<String, NamespaceId>,
+    index: &mut HashMap<NamespaceId, Index>,
+    config: &IndexConfig,
+    next_namespace: &mut NamespaceId,
+    kv: &KeyValuePair,
+) -> io::Result<()> {
+    let name = String::from_utf8(kv.key.clone())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    if kv.value.is_empty() {
+        if let Some(id) = namespaces.remove(&name) {
+            index.remove(&id);
+        }
+        return Ok(());
+    }
+
+    let bytes: [u8; 4] = kv.value.as_slice().try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed namespace catalog record",
+        )
+    })?;
+    let id = NamespaceId::from_le_bytes(bytes);
+
+    namespaces.insert(name, id);
+    index.entry(id).or_insert_with(|| config.new_index());
+    *next_namespace = (*next_namespace).max(id + 1);
+
+    Ok(())
+}
+
+impl ActionKV {
+    pub(crate) fn reset_namespaces(&mut self) {
+        self.namespaces.clear();
+        self.namespaces
+            .insert(String::from(DEFAULT_NAMESPACE_NAME), DEFAULT_NAMESPACE);
+        self.index.clear();
+        self.index
+            .insert(DEFAULT_NAMESPACE, self.index_config.new_index());
+        self.next_namespace = DEFAULT_NAMESPACE + 1;
+    }
+
+    /// Ids are not handed out twice while records of a dropped namespace may still be in the
+    /// log, so a namespace dropped and created again starts empty
+    pub fn create_namespace(&mut self, name: &str) -> io::Result<NamespaceId> {
+        if self.namespaces.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("namespace {} already exists", name),
+            ));
+        }
+
+        let id = self.next_namespace;
+        if id >= MERGE_NAMESPACE {
+            return Err(io::Error::other("namespace ids exhausted"));
+        }
+
+        self.append(CATALOG_NAMESPACE, name.as_bytes(), &id.to_le_bytes())?;
+        self.counters.live_bytes += catalog_record_len(name);
+        self.namespaces.insert(name.to_string(), id);
+        self.index.insert(id, self.index_config.new_index());
+        self.next_namespace = id + 1;
+
+        Ok(id)
+    }
+
+    pub fn namespace(&self, name: &str) -> Option<NamespaceId> {
+        self.namespaces.get(name).copied()
+    }
+
+    /// Names of every live namespace, sorted
+    pub fn list_namespaces(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.namespaces.keys().map(|name| name.as_str()).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// The records of a dropped namespace stay in the log until the next `compact`
+    pub fn drop_namespace(&mut self, name: &str) -> io::Result<()> {
+        let id = match self.namespaces.get(name) {
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("namespace {} does not exist", name),
+                ))
+            }
+            Some(id) => *id,
+        };
+
+        if id == DEFAULT_NAMESPACE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the default namespace cannot be dropped",
+            ));
+        }
+
+        self.append(CATALOG_NAMESPACE, name.as_bytes(), b"")?;
+        self.counters.live_bytes -= catalog_record_len(name);
+        self.namespaces.remove(name);
+        self.cache.remove_namespace(id);
+        if let Some(index) = self.index.remove(&id) {
+            self.counters.live_bytes = self.counters.live_bytes.saturating_sub(index.live_bytes());
+        }
+        self.secondary.retain(|_, index| index.namespace() != id);
+        self.merkle.remove(&id);
+
+        self.maybe_compact();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::storage::tests::open_memory;
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::DEFAULT_NAMESPACE;
+    use std::io;
+
+    #[test]
+    pub fn test_namespaces_are_isolated() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        let users = akv.create_namespace("users").unwrap();
+        let orders = akv.create_namespace("orders").unwrap();
+        assert_eq!(akv.list_namespaces(), vec!["default", "orders", "users"]);
+
+        akv.insert_in(users, b"1", b"vlad").unwrap();
+        akv.insert_in(orders, b"1", b"book").unwrap();
+        akv.insert(b"1", b"plain").unwrap();
+
+        assert_eq!(akv.get_in(users, b"1").unwrap(), Some(b"vlad".to_vec()));
+        assert_eq!(akv.get_in(orders, b"1").unwrap(), Some(b"book".to_vec()));
+        assert_eq!(akv.get(b"1").unwrap(), Some(b"plain".to_vec()));
+
+        akv.delete_in(orders, b"1").unwrap();
+        assert_eq!(akv.keys_in(orders).count(), 0);
+        assert_eq!(akv.keys_in(users).count(), 1);
+
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.namespace("users"), Some(users));
+        assert_eq!(akv.get_in(users, b"1").unwrap(), Some(b"vlad".to_vec()));
+        assert_eq!(akv.get_in(orders, b"1").unwrap(), None);
+    }
+
+    #[test]
+    pub fn test_drop_namespace_reclaimed_by_compaction() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        let tmp = akv.create_namespace("tmp").unwrap();
+        akv.insert_in(tmp, b"big", &[7; 512]).unwrap();
+        akv.insert(b"keep", b"me").unwrap();
+
+        akv.drop_namespace("tmp").unwrap();
+        assert_eq!(akv.namespace("tmp"), None);
+        let err = akv.insert_in(tmp, b"big", b"again").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = akv.drop_namespace("default").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let before = storage.size().unwrap();
+        akv.compact().unwrap();
+        let after = storage.size().unwrap();
+        assert!(after < before);
+
+        // A recreated namespace gets a fresh id and none of the old records
+        let tmp_again = akv.create_namespace("tmp").unwrap();
+        assert_ne!(tmp, tmp_again);
+
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.get(b"keep").unwrap(), Some(b"me".to_vec()));
+        assert_eq!(akv.keys_in(tmp_again).count(), 0);
+        assert!(akv.get_in(tmp, b"big").is_err());
+        assert_eq!(akv.list_namespaces(), vec!["default", "tmp"]);
+        assert_eq!(akv.keys_in(DEFAULT_NAMESPACE).count(), 1);
+    }
+}
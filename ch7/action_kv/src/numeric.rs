@@ -0,0 +1,253 @@
+//! Read-modify-write operations. Each one reads and writes the key within a single call on the
+//! store, so callers sharing it through an `AsyncHandle` or a `ShardedStore` no longer race
+//! between their own `get` and `insert`.
+
+use crate::{ActionKV, ByteStr, ByteString, NamespaceId, DEFAULT_NAMESPACE};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Counters are stored as 8 byte little endian `i64`s, a missing key counts as 0
+pub const COUNTER_LEN: usize = 8;
+
+/// What `increment` does when the result does not fit in an `i64`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Leave the counter as it is and fail with an `Overflow`
+    #[default]
+    Fail,
+    /// Wrap around in two's complement
+    Wrap,
+    /// Stop at `i64::MAX` or `i64::MIN`
+    Saturate,
+}
+
+impl OverflowPolicy {
+    /// `None` when the policy refuses the result. `delta` is wide enough for `-i64::MIN`
+    fn apply(self, value: i64, delta: i128) -> Option<i64> {
+        let exact = value as i128 + delta;
+        match self {
+            OverflowPolicy::Fail => i64::try_from(exact).ok(),
+            OverflowPolicy::Wrap => Some(exact as i64),
+            OverflowPolicy::Saturate => {
+                Some(exact.clamp(i64::MIN as i128, i64::MAX as i128) as i64)
+            }
+        }
+    }
+}
+
+/// Carried inside the `io::Error` returned by `increment` under `OverflowPolicy::Fail`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overflow {
+    pub namespace: NamespaceId,
+    pub key: ByteString,
+    pub value: i64,
+    /// Negative for a decrement
+    pub delta: i128,
+}
+
+impl Overflow {
+    pub fn from_io(e: &io::Error) -> Option<&Overflow> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "adding {} to counter {} ({}) overflows",
+            self.delta,
+            String::from_utf8_lossy(&self.key),
+            self.value
+        )
+    }
+}
+
+impl Error for Overflow {}
+
+fn decode(key: &ByteStr, value: &ByteStr) -> io::Result<i64> {
+    let bytes: [u8; COUNTER_LEN] = value.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} holds {} bytes, not a counter",
+                String::from_utf8_lossy(key),
+                value.len()
+            ),
+        )
+    })?;
+    Ok(i64::from_le_bytes(bytes))
+}
+
+impl ActionKV {
+    pub fn counter(&mut self, key: &ByteStr) -> io::Result<i64> {
+        self.counter_in(DEFAULT_NAMESPACE, key)
+    }
+
+    /// Current value of a counter, 0 when the key does not exist
+    pub fn counter_in(&mut self, namespace: NamespaceId, key: &ByteStr) -> io::Result<i64> {
+        match self.get_in(namespace, key)? {
+            None => Ok(0),
+            Some(value) => decode(key, &value),
+        }
+    }
+
+    pub fn increment(&mut self, key: &ByteStr, delta: i64) -> io::Result<i64> {
+        self.increment_in(DEFAULT_NAMESPACE, key, delta)
+    }
+
+    /// Adds `delta` to the counter at `key` and returns the new value. Overflow is handled as
+    /// `Options::overflow` says
+    pub fn increment_in(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        delta: i64,
+    ) -> io::Result<i64> {
+        self.add_to_counter(namespace, key, delta as i128)
+    }
+
+    pub fn decrement(&mut self, key: &ByteStr, delta: i64) -> io::Result<i64> {
+        self.decrement_in(DEFAULT_NAMESPACE, key, delta)
+    }
+
+    pub fn decrement_in(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        delta: i64,
+    ) -> io::Result<i64> {
+        self.add_to_counter(namespace, key, -(delta as i128))
+    }
+
+    fn add_to_counter(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        delta: i128,
+    ) -> io::Result<i64> {
+        let value = self.counter_in(namespace, key)?;
+        let new_value = self.overflow.apply(value, delta).ok_or_else(|| {
+            io::Error::other(Overflow {
+                namespace,
+                key: key.to_vec(),
+                value,
+                delta,
+            })
+        })?;
+
+        self.insert_in(namespace, key, &new_value.to_le_bytes())?;
+        Ok(new_value)
+    }
+
+    pub fn append_value(&mut self, key: &ByteStr, suffix: &ByteStr) -> io::Result<usize> {
+        self.append_value_in(DEFAULT_NAMESPACE, key, suffix)
+    }
+
+    /// Adds `suffix` to the end of the value at `key`, creating it when missing, and returns
+    /// the new length. The whole value is written again, it is the log that only appends
+    pub fn append_value_in(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        suffix: &ByteStr,
+    ) -> io::Result<usize> {
+        let mut value = self.get_in(namespace, key)?.unwrap_or_default();
+        value.extend_from_slice(suffix);
+        if !value.is_empty() {
+            self.insert_in(namespace, key, &value)?;
+        }
+        Ok(value.len())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Overflow, OverflowPolicy};
+    use crate::handle::AsyncHandle;
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+    use crate::{ActionKV, Options};
+    use futures::executor::block_on;
+    use std::thread;
+
+    fn with_policy(overflow: OverflowPolicy) -> ActionKV {
+        let options = Options {
+            overflow,
+            ..Options::default()
+        };
+        ActionKV::with_storage(Box::new(MemoryStorage::new()), options).unwrap()
+    }
+
+    #[test]
+    pub fn test_counters_and_overflow() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.counter(b"hits").unwrap(), 0);
+        assert_eq!(akv.increment(b"hits", 5).unwrap(), 5);
+        assert_eq!(akv.decrement(b"hits", 7).unwrap(), -2);
+        assert_eq!(akv.append_value(b"log", b"ab").unwrap(), 2);
+        assert_eq!(akv.append_value(b"log", b"cd").unwrap(), 4);
+        assert_eq!(akv.get(b"log").unwrap(), Some(b"abcd".to_vec()));
+
+        akv.insert(b"name", b"vlad").unwrap();
+        assert!(akv.increment(b"name", 1).is_err());
+
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.counter(b"hits").unwrap(), -2);
+        assert_eq!(
+            akv.get(b"hits").unwrap(),
+            Some((-2i64).to_le_bytes().to_vec())
+        );
+        akv.increment(b"big", i64::MAX).unwrap();
+        let err = akv.increment(b"big", 1).unwrap_err();
+        assert_eq!(
+            Overflow::from_io(&err),
+            Some(&Overflow {
+                namespace: 0,
+                key: b"big".to_vec(),
+                value: i64::MAX,
+                delta: 1,
+            })
+        );
+        assert_eq!(akv.counter(b"big").unwrap(), i64::MAX);
+        assert_eq!(
+            akv.decrement(b"big", i64::MIN).unwrap_err().kind(),
+            err.kind()
+        );
+        assert_eq!(akv.decrement(b"hits", i64::MIN).unwrap(), i64::MAX - 1);
+
+        let mut wrap = with_policy(OverflowPolicy::Wrap);
+        wrap.increment(b"n", i64::MAX).unwrap();
+        assert_eq!(wrap.increment(b"n", 1).unwrap(), i64::MIN);
+
+        let mut saturate = with_policy(OverflowPolicy::Saturate);
+        assert_eq!(saturate.decrement(b"n", i64::MAX).unwrap(), -i64::MAX);
+        assert_eq!(saturate.decrement(b"n", 10).unwrap(), i64::MIN);
+    }
+
+    #[test]
+    pub fn test_concurrent_increments_are_not_lost() {
+        let storage = MemoryStorage::new();
+        let handle = AsyncHandle::spawn(open_memory(&storage), 8).unwrap();
+
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let handle = handle.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        block_on(handle.increment(b"hits", 1)).unwrap();
+                        block_on(handle.append_value(b"trail", b".")).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(block_on(handle.increment(b"hits", 0)).unwrap(), 800);
+        assert_eq!(block_on(handle.get(b"trail")).unwrap().unwrap().len(), 800);
+    }
+}
@@ -0,0 +1,209 @@
+//! Running out of room. A store can be given a quota on the size of its log, and a write that
+//! fails because the disk is full is rolled back and leaves the store read-only, rather than
+//! letting the next record land behind a torn one.
+
+use crate::ActionKV;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Carried inside the `io::Error` returned by a write that would take the log past
+/// `Options::quota`. Nothing was written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub quota: u64,
+    pub log_len: u64,
+    pub record_len: u64,
+}
+
+impl QuotaExceeded {
+    pub fn from_io(e: &io::Error) -> Option<&QuotaExceeded> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "a record of {} bytes would take the log from {} past its quota of {} bytes, \
+             compact the store or raise the quota",
+            self.record_len, self.log_len, self.quota
+        )
+    }
+}
+
+impl Error for QuotaExceeded {}
+
+/// Carried inside the `io::Error` returned by every write once the store has gone read-only
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadOnly {
+    /// The error of the write that failed
+    pub cause: String,
+}
+
+impl ReadOnly {
+    pub fn from_io(e: &io::Error) -> Option<&ReadOnly> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for ReadOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the store is read-only after a failed write ({}), free some space and call \
+             resume_writes, or compact it",
+            self.cause
+        )
+    }
+}
+
+impl Error for ReadOnly {}
+
+/// Whether `e` says the disk or the user's disk quota is full
+pub fn is_out_of_space(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded | io::ErrorKind::FileTooLarge
+    )
+}
+
+impl ActionKV {
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    /// `None` removes the quota. A log already past a new quota only takes no more writes
+    pub fn set_quota(&mut self, quota: Option<u64>) {
+        self.quota = quota;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.is_some()
+    }
+
+    /// Takes writes again once space has been freed. Should the disk still be full, the next
+    /// write fails and the store goes back to read-only
+    pub fn resume_writes(&mut self) {
+        self.read_only = None;
+    }
+
+    /// Before a record of `record_len` bytes is appended
+    pub(crate) fn check_room(&self, record_len: u64) -> io::Result<()> {
+        if let Some(cause) = &self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                ReadOnly {
+                    cause: cause.clone(),
+                },
+            ));
+        }
+
+        let log_len = self.counters.log_len;
+        match self.quota {
+            Some(quota) if log_len + record_len > quota => Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                QuotaExceeded {
+                    quota,
+                    log_len,
+                    record_len,
+                },
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Cuts off whatever part of the record made it to the log before `e`, so the next one does
+    /// not land behind it. Running out of space, or not managing to cut it off, leaves the
+    /// store read-only
+    pub(crate) fn append_failed(&mut self, log_len: u64, e: io::Error) -> io::Error {
+        let rolled_back = self.f.truncate(log_len);
+        if is_out_of_space(&e) || rolled_back.is_err() {
+            self.read_only = Some(e.to_string());
+        }
+        e
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{QuotaExceeded, ReadOnly};
+    use crate::storage::tests::open_memory;
+    use crate::storage::{FaultyStorage, MemoryStorage, Storage};
+    use crate::{ActionKV, Options};
+    use std::io;
+
+    #[test]
+    pub fn test_quota_refuses_writes_until_compaction() {
+        let storage = MemoryStorage::new();
+        let options = Options {
+            quota: Some(120),
+            ..Options::default()
+        };
+        let mut akv = ActionKV::with_storage(Box::new(storage.clone()), options).unwrap();
+
+        // Header of 8 bytes, then records of 16 + 4 + 8 bytes
+        for _ in 0..4 {
+            akv.insert(b"name", b"vlad the").unwrap();
+        }
+        let full = storage.size().unwrap();
+        assert_eq!(full, 120);
+        let err = akv.insert(b"name", b"impaler!").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::QuotaExceeded);
+        assert_eq!(
+            QuotaExceeded::from_io(&err),
+            Some(&QuotaExceeded {
+                quota: 120,
+                log_len: 120,
+                record_len: 28,
+            })
+        );
+        assert_eq!(storage.size().unwrap(), full);
+        assert!(!akv.is_read_only());
+
+        akv.compact().unwrap();
+        akv.insert(b"name", b"impaler!").unwrap();
+        akv.set_quota(None);
+        assert_eq!(akv.quota(), None);
+        akv.insert(b"other", b"value").unwrap();
+        assert_eq!(akv.get(b"name").unwrap(), Some(b"impaler!".to_vec()));
+    }
+
+    #[test]
+    pub fn test_disk_full_rolls_back_and_goes_read_only() {
+        let memory = MemoryStorage::new();
+        let storage = FaultyStorage::new(memory.clone());
+        let faults = storage.faults();
+        let mut akv = ActionKV::with_storage(Box::new(storage), Options::default()).unwrap();
+        akv.insert(b"a", b"1").unwrap();
+        let committed = memory.size().unwrap();
+
+        faults.tear_writes_after(10);
+        faults.error_kind(io::ErrorKind::StorageFull);
+        let err = akv.insert(b"b", b"2").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(memory.size().unwrap(), committed);
+        assert!(akv.is_read_only());
+
+        // Even with room again, writes wait to be resumed
+        faults.clear();
+        let err = akv.delete(b"a").unwrap_err();
+        assert!(ReadOnly::from_io(&err).unwrap().cause.contains("injected"));
+        assert_eq!(akv.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        akv.resume_writes();
+        akv.insert(b"b", b"2").unwrap();
+        faults.fail_writes_after(0);
+        faults.error_kind(io::ErrorKind::StorageFull);
+        assert!(akv.insert(b"c", b"3").is_err());
+        faults.clear();
+        akv.compact().unwrap();
+        assert!(!akv.is_read_only());
+        akv.insert(b"c", b"3").unwrap();
+
+        let mut akv = open_memory(&memory);
+        assert_eq!(akv.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(akv.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+}
@@ -0,0 +1,258 @@
+use crate::{ActionKV, ByteStr, ByteString, NamespaceId, DEFAULT_NAMESPACE};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+
+/// Pulls the index terms out of a value. A value may map to any number of terms, including none
+pub type Extractor = Box<dyn Fn(&ByteStr) -> Vec<ByteString> + Send>;
+
+/// In memory index from terms found inside values to the primary keys holding them
+pub struct SecondaryIndex {
+    namespace: NamespaceId,
+    extract: Extractor,
+    postings: HashMap<ByteString, HashSet<ByteString>>,
+    terms: HashMap<ByteString, Vec<ByteString>>,
+}
+
+impl fmt::Debug for SecondaryIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecondaryIndex")
+            .field("namespace", &self.namespace)
+            .field("terms", &self.postings.len())
+            .field("keys", &self.terms.len())
+            .finish()
+    }
+}
+
+impl SecondaryIndex {
+    pub fn namespace(&self) -> NamespaceId {
+        self.namespace
+    }
+
+    fn remove(&mut self, key: &ByteStr) {
+        let terms = match self.terms.remove(key) {
+            None => return,
+            Some(terms) => terms,
+        };
+
+        for term in terms {
+            if let Some(keys) = self.postings.get_mut(&term) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, key: &ByteStr, value: &ByteStr) {
+        let mut terms = (self.extract)(value);
+        terms.sort_unstable();
+        terms.dedup();
+
+        for term in &terms {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(key.to_vec());
+        }
+
+        if !terms.is_empty() {
+            self.terms.insert(key.to_vec(), terms);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.postings.clear();
+        self.terms.clear();
+    }
+}
+
+impl ActionKV {
+    /// Registers an index over the values of the default namespace, see `register_index_in`
+    pub fn register_index<F>(&mut self, name: &str, extractor: F) -> io::Result<()>
+    where
+        F: Fn(&ByteStr) -> Vec<ByteString> + Send + 'static,
+    {
+        self.register_index_in(DEFAULT_NAMESPACE, name, extractor)
+    }
+
+    /// Registers an index over the values of `namespace`. Indexes are not persisted, they are
+    /// built from the records already loaded and kept up to date by every later write and `load`
+    pub fn register_index_in<F>(
+        &mut self,
+        namespace: NamespaceId,
+        name: &str,
+        extractor: F,
+    ) -> io::Result<()>
+    where
+        F: Fn(&ByteStr) -> Vec<ByteString> + Send + 'static,
+    {
+        if self.secondary.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("index {} already exists", name),
+            ));
+        }
+        self.namespace_index(namespace)?;
+
+        let mut index = SecondaryIndex {
+            namespace,
+            extract: Box::new(extractor),
+            postings: HashMap::new(),
+            terms: HashMap::new(),
+        };
+        self.fill_secondary_index(&mut index)?;
+        self.secondary.insert(name.to_string(), index);
+
+        Ok(())
+    }
+
+    pub fn unregister_index(&mut self, name: &str) -> bool {
+        self.secondary.remove(name).is_some()
+    }
+
+    /// Primary keys whose value produced `term` under the index `name`, sorted
+    pub fn get_by_index(&self, name: &str, term: &ByteStr) -> io::Result<Vec<ByteString>> {
+        let index = self.secondary.get(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("index {} does not exist", name),
+            )
+        })?;
+
+        let mut keys: Vec<ByteString> = index
+            .postings
+            .get(term)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default();
+        keys.sort_unstable();
+
+        Ok(keys)
+    }
+
+    /// `value` is `None` when the key was deleted
+    pub(crate) fn update_secondary_indexes(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        value: Option<&ByteStr>,
+    ) {
+        for index in self.secondary.values_mut() {
+            if index.namespace != namespace {
+                continue;
+            }
+
+            index.remove(key);
+            if let Some(value) = value {
+                index.add(key, value);
+            }
+        }
+    }
+
+    pub(crate) fn rebuild_secondary_indexes(&mut self) -> io::Result<()> {
+        let mut secondary = std::mem::take(&mut self.secondary);
+
+        let mut result = Ok(());
+        for index in secondary.values_mut() {
+            index.clear();
+            if result.is_ok() {
+                result = self.fill_secondary_index(index);
+            }
+        }
+
+        self.secondary = secondary;
+        result
+    }
+
+    fn fill_secondary_index(&mut self, index: &mut SecondaryIndex) -> io::Result<()> {
+        let entries = match self.index.get(&index.namespace) {
+            None => return Ok(()),
+            Some(primary) => primary.entries(),
+        };
+
+        for entry in entries {
+            let (key, position) = entry?;
+            let value = self.value_at(index.namespace, position)?;
+            index.add(&key, &value);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+    use crate::{ActionKV, Options};
+    use std::io;
+
+    /// Values look like "city,language", index the city
+    fn city(value: &[u8]) -> Vec<Vec<u8>> {
+        value
+            .split(|b| *b == b',')
+            .next()
+            .map(|city| vec![city.to_vec()])
+            .unwrap_or_default()
+    }
+
+    #[test]
+    pub fn test_index_follows_writes() {
+        let mut akv = open_memory(&MemoryStorage::new());
+        akv.insert(b"vlad", b"iasi,ro").unwrap();
+        akv.register_index("city", city).unwrap();
+
+        akv.insert(b"ana", b"iasi,en").unwrap();
+        akv.insert(b"tom", b"cluj,en").unwrap();
+        assert_eq!(
+            akv.get_by_index("city", b"iasi").unwrap(),
+            vec![b"ana".to_vec(), b"vlad".to_vec()]
+        );
+
+        akv.insert(b"vlad", b"cluj,ro").unwrap();
+        akv.delete(b"tom").unwrap();
+        assert_eq!(
+            akv.get_by_index("city", b"iasi").unwrap(),
+            vec![b"ana".to_vec()]
+        );
+        assert_eq!(
+            akv.get_by_index("city", b"cluj").unwrap(),
+            vec![b"vlad".to_vec()]
+        );
+
+        let err = akv.register_index("city", city).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let err = akv.get_by_index("language", b"ro").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    pub fn test_index_rebuilt_on_load() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        let people = akv.create_namespace("people").unwrap();
+        akv.insert_in(people, b"vlad", b"iasi,ro").unwrap();
+        akv.insert_in(people, b"ana", b"iasi,en").unwrap();
+        akv.delete_in(people, b"ana").unwrap();
+        akv.insert(b"other", b"iasi,xx").unwrap();
+
+        let mut akv = ActionKV::with_storage(Box::new(storage), Options::default()).unwrap();
+        akv.register_index_in(people, "city", city).unwrap_err();
+        akv.load().unwrap();
+        akv.register_index_in(people, "city", city).unwrap();
+        assert_eq!(
+            akv.get_by_index("city", b"iasi").unwrap(),
+            vec![b"vlad".to_vec()]
+        );
+
+        akv.load().unwrap();
+        assert_eq!(
+            akv.get_by_index("city", b"iasi").unwrap(),
+            vec![b"vlad".to_vec()]
+        );
+
+        akv.drop_namespace("people").unwrap();
+        assert!(akv.get_by_index("city", b"iasi").is_err());
+    }
+}
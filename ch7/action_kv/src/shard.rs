@@ -0,0 +1,321 @@
+//! Keys spread over several independent stores by hash. Every shard sits on its own I/O thread
+//! behind an `AsyncHandle`, so writes to different shards no longer queue up behind one writer.
+
+use crate::handle::{self, AsyncHandle};
+use crate::storage::sync_parent;
+use crate::{ActionKV, ByteStr, ByteString, Options};
+use futures::executor;
+use futures::future::try_join_all;
+use std::fs;
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// Jobs waiting for each shard before writers have to wait
+const SHARD_QUEUE: usize = 64;
+/// File of a sharded directory holding its shard count in decimal. It is written once every
+/// shard opened, so shard files without it are left over by an open that failed and hold nothing
+const MANIFEST: &str = "shards";
+
+/// The methods wait for the shards on the calling thread. Call them from plain threads: inside
+/// an async task they stall the executor's worker until the shard answers, and from a job on a
+/// shard's I/O thread they would wait for that same thread, so they fail with
+/// `io::ErrorKind::Deadlock` there instead. Async code goes through `shard` and `shard_of`
+#[derive(Debug, Clone)]
+pub struct ShardedStore {
+    shards: Vec<AsyncHandle>,
+}
+
+fn block_on<T>(call: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    if handle::on_io_thread() {
+        return Err(io::Error::new(
+            io::ErrorKind::Deadlock,
+            "a blocking ShardedStore call from a job on an I/O thread would wait for itself",
+        ));
+    }
+    executor::block_on(call)
+}
+
+fn shard_file_name(shard: usize) -> String {
+    format!("shard-{:03}.akv", shard)
+}
+
+impl ShardedStore {
+    /// Opens or creates `shards` logs inside `dir`. A directory keeps the shard count it was
+    /// created with, opening it with another one would send keys to the wrong shard
+    pub fn open(dir: &Path, shards: usize, options: Options) -> io::Result<ShardedStore> {
+        fs::create_dir_all(dir)?;
+
+        let manifest = dir.join(MANIFEST);
+        let existing = match fs::read_to_string(&manifest) {
+            Ok(count) => Some(count.trim().parse::<usize>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} does not hold a shard count", manifest.display()),
+                )
+            })?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        match existing {
+            Some(existing) if existing != shards => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} holds {} shards, not {}",
+                        dir.display(),
+                        existing,
+                        shards
+                    ),
+                ))
+            }
+            _ => {}
+        }
+        if shards == 0 {
+            // Refused before a count of 0 gets recorded
+            return ShardedStore::from_stores(Vec::new());
+        }
+
+        let mut stores = Vec::with_capacity(shards);
+        for shard in 0..shards {
+            let mut store =
+                ActionKV::open_with(&dir.join(shard_file_name(shard)), options.clone())?;
+            store.load()?;
+            stores.push(store);
+        }
+
+        if existing.is_none() {
+            // Renamed into place, so that a crash never leaves a torn count behind
+            let scratch = dir.join(format!("{}.tmp", MANIFEST));
+            let mut f = File::create(&scratch)?;
+            writeln!(f, "{}", shards)?;
+            f.sync_all()?;
+            fs::rename(&scratch, &manifest)?;
+            sync_parent(&manifest)?;
+        }
+        ShardedStore::from_stores(stores)
+    }
+
+    /// Shards over stores that are already open and loaded, keys go to the store at
+    /// `hash(key) % stores.len()`
+    pub fn from_stores(stores: Vec<ActionKV>) -> io::Result<ShardedStore> {
+        if stores.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a sharded store needs at least one shard",
+            ));
+        }
+
+        let shards = stores
+            .into_iter()
+            .map(|store| AsyncHandle::spawn(store, SHARD_QUEUE))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(ShardedStore { shards })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The hash has to stay the same across runs and builds, so it is a crc32 of the key
+    pub fn shard_of(&self, key: &ByteStr) -> usize {
+        crc::crc32::checksum_ieee(key) as usize % self.shards.len()
+    }
+
+    /// Handle of one shard, for async callers and for anything the sharded API does not cover
+    pub fn shard(&self, shard: usize) -> &AsyncHandle {
+        &self.shards[shard]
+    }
+
+    fn shard_for(&self, key: &ByteStr) -> &AsyncHandle {
+        &self.shards[self.shard_of(key)]
+    }
+
+    pub fn get(&self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        block_on(self.shard_for(key).get(key))
+    }
+
+    pub fn contains_key(&self, key: &ByteStr) -> io::Result<bool> {
+        let key = key.to_vec();
+        block_on(
+            self.shard_for(&key)
+                .call(move |store| store.contains_key(&key)),
+        )
+    }
+
+    pub fn insert(&self, key: &ByteStr, val: &ByteStr) -> io::Result<()> {
+        block_on(self.shard_for(key).insert(key, val))
+    }
+
+    pub fn delete(&self, key: &ByteStr) -> io::Result<()> {
+        block_on(self.shard_for(key).delete(key))
+    }
+
+    pub fn increment(&self, key: &ByteStr, delta: i64) -> io::Result<i64> {
+        block_on(self.shard_for(key).increment(key, delta))
+    }
+
+    pub fn decrement(&self, key: &ByteStr, delta: i64) -> io::Result<i64> {
+        block_on(self.shard_for(key).decrement(key, delta))
+    }
+
+    pub fn append_value(&self, key: &ByteStr, suffix: &ByteStr) -> io::Result<usize> {
+        block_on(self.shard_for(key).append_value(key, suffix))
+    }
+
+    /// Key-value pairs of every shard whose key starts with `prefix`, sorted by key. The shards
+    /// are scanned concurrently, each one on its own thread
+    pub fn scan(&self, prefix: &ByteStr) -> io::Result<Vec<(ByteString, ByteString)>> {
+        let scans = self.shards.iter().map(|shard| shard.scan(prefix));
+        let mut pairs: Vec<(ByteString, ByteString)> = block_on(try_join_all(scans))?
+            .into_iter()
+            .flatten()
+            .collect();
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+
+    /// Keys stored in every shard
+    pub fn len(&self) -> io::Result<usize> {
+        let counts = self
+            .shards
+            .iter()
+            .map(|shard| shard.call(|store| Ok(store.stats().live_keys)));
+        Ok(block_on(try_join_all(counts))?.into_iter().sum())
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Compacts all shards at the same time
+    pub fn compact(&self) -> io::Result<()> {
+        let compactions = self
+            .shards
+            .iter()
+            .map(|shard| shard.call(|store| store.compact()));
+        block_on(try_join_all(compactions))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{shard_file_name, ShardedStore};
+    use crate::storage::tests::{open_memory, temp_dir};
+    use crate::storage::MemoryStorage;
+    use crate::Options;
+    use futures::executor::block_on;
+    use std::sync::Arc;
+    use std::thread;
+    use std::{fs, io};
+
+    #[test]
+    pub fn test_keys_spread_over_shards() {
+        let storages: Vec<MemoryStorage> = (0..4).map(|_| MemoryStorage::new()).collect();
+        let stores = storages.iter().map(open_memory).collect();
+        let store = Arc::new(ShardedStore::from_stores(stores).unwrap());
+
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        let key = format!("key:{:02}", writer * 25 + i);
+                        store.insert(key.as_bytes(), b"value").unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(store.len().unwrap(), 100);
+        store.delete(b"key:42").unwrap();
+        assert!(!store.contains_key(b"key:42").unwrap());
+        assert_eq!(store.get(b"key:07").unwrap(), Some(b"value".to_vec()));
+
+        let scanned = store.scan(b"key:4").unwrap();
+        let keys: Vec<&[u8]> = scanned.iter().map(|(key, _)| key.as_slice()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                &b"key:40"[..],
+                b"key:41",
+                b"key:43",
+                b"key:44",
+                b"key:45",
+                b"key:46",
+                b"key:47",
+                b"key:48",
+                b"key:49",
+            ]
+        );
+
+        store.compact().unwrap();
+        drop(store);
+        assert!(ShardedStore::from_stores(Vec::new()).is_err());
+
+        // Every shard got a part of the keys and only the keys that hash to it
+        for (shard, storage) in storages.iter().enumerate() {
+            let akv = open_memory(storage);
+            assert!(akv.keys().count() > 0);
+            assert!(akv
+                .keys()
+                .all(|key| crc::crc32::checksum_ieee(&key.unwrap()) as usize % 4 == shard));
+        }
+    }
+
+    #[test]
+    pub fn test_open_keeps_shard_count() {
+        let temp = temp_dir();
+        let dir = temp.path();
+
+        let store = ShardedStore::open(dir, 3, Options::default()).unwrap();
+        store.insert(b"vlad", b"onis").unwrap();
+        drop(store);
+
+        assert!(ShardedStore::open(dir, 2, Options::default()).is_err());
+        let store = ShardedStore::open(dir, 3, Options::default()).unwrap();
+        assert_eq!(store.get(b"vlad").unwrap(), Some(b"onis".to_vec()));
+    }
+
+    #[test]
+    pub fn test_failed_open_leaves_no_shard_count() {
+        let temp = temp_dir();
+        let dir = temp.path();
+        // The second shard cannot be opened, the first one gets created all the same
+        fs::create_dir_all(dir.join(shard_file_name(1))).unwrap();
+        assert!(ShardedStore::open(dir, 3, Options::default()).is_err());
+        assert!(dir.join(shard_file_name(0)).exists());
+
+        fs::remove_dir(dir.join(shard_file_name(1))).unwrap();
+        let store = ShardedStore::open(dir, 2, Options::default()).unwrap();
+        store.insert(b"vlad", b"onis").unwrap();
+        drop(store);
+
+        assert!(ShardedStore::open(dir, 3, Options::default()).is_err());
+        let store = ShardedStore::open(dir, 2, Options::default()).unwrap();
+        assert_eq!(store.get(b"vlad").unwrap(), Some(b"onis".to_vec()));
+    }
+
+    #[test]
+    pub fn test_blocking_call_from_io_thread_fails() {
+        let stores = (0..2).map(|_| open_memory(&MemoryStorage::new())).collect();
+        let store = ShardedStore::from_stores(stores).unwrap();
+        store.insert(b"vlad", b"onis").unwrap();
+
+        let inner = store.clone();
+        let kind = block_on(
+            store
+                .shard(0)
+                .call(move |_| Ok(inner.get(b"vlad").unwrap_err().kind())),
+        )
+        .unwrap();
+        assert_eq!(kind, io::ErrorKind::Deadlock);
+        assert_eq!(store.get(b"vlad").unwrap(), Some(b"onis".to_vec()));
+    }
+}
@@ -0,0 +1,267 @@
+use crate::index::Slot;
+use crate::namespace::{catalog_record_len, DEFAULT_NAMESPACE};
+use crate::{ActionKV, FILE_HEADER_LEN};
+use std::io;
+use std::time::SystemTime;
+
+/// Snapshot of the space used by a store, see `ActionKV::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub live_keys: usize,
+    pub total_records: u64,
+    /// Bytes taken by stale versions, tombstones and records of dropped namespaces
+    pub dead_bytes: u64,
+    pub file_size: u64,
+    /// `dead_bytes / file_size`, 0 for an empty log
+    pub fragmentation: f64,
+    /// Only compactions run since the store was opened are known
+    pub last_compaction: Option<SystemTime>,
+    /// Error of the last automatic compaction, cleared by the next one that succeeds. The
+    /// write that started it went through all the same
+    pub auto_compaction_error: Option<String>,
+}
+
+/// Compacts the log once the dead bytes make up more than `dead_ratio` of it. Small logs are
+/// left alone until they hold at least `min_dead_bytes` of garbage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoCompaction {
+    pub dead_ratio: f64,
+    pub min_dead_bytes: u64,
+}
+
+impl Default for AutoCompaction {
+    fn default() -> AutoCompaction {
+        AutoCompaction {
+            dead_ratio: 0.5,
+            min_dead_bytes: 1 << 20,
+        }
+    }
+}
+
+/// Kept up to date by every append so that `stats` does not have to walk the log
+#[derive(Debug)]
+pub(crate) struct Counters {
+    pub(crate) total_records: u64,
+    pub(crate) live_bytes: u64,
+    pub(crate) log_len: u64,
+    last_compaction: Option<SystemTime>,
+    /// Compactions since the store was opened. Offsets read before one can be reused after it
+    pub(crate) compactions: u64,
+    auto_compaction: Option<AutoCompaction>,
+    auto_compaction_error: Option<String>,
+}
+
+impl Counters {
+    pub(crate) fn new(log_len: u64) -> Counters {
+        Counters {
+            total_records: 0,
+            live_bytes: FILE_HEADER_LEN,
+            log_len,
+            last_compaction: None,
+            compactions: 0,
+            auto_compaction: None,
+            auto_compaction_error: None,
+        }
+    }
+
+    pub(crate) fn appended(&mut self, log_len: u64) {
+        self.total_records += 1;
+        self.log_len = log_len;
+    }
+
+    pub(crate) fn compacted(&mut self, total_records: u64, log_len: u64) {
+        self.total_records = total_records;
+        self.live_bytes = log_len;
+        self.log_len = log_len;
+        self.last_compaction = Some(SystemTime::now());
+        self.compactions += 1;
+    }
+
+    fn dead_bytes(&self) -> u64 {
+        self.log_len.saturating_sub(self.live_bytes)
+    }
+}
+
+impl ActionKV {
+    pub fn stats(&self) -> Stats {
+        let dead_bytes = self.counters.dead_bytes();
+        let file_size = self.counters.log_len;
+        let fragmentation = if file_size == 0 {
+            0.0
+        } else {
+            dead_bytes as f64 / file_size as f64
+        };
+
+        Stats {
+            live_keys: self.index.values().map(|index| index.len()).sum(),
+            total_records: self.counters.total_records,
+            dead_bytes,
+            file_size,
+            fragmentation,
+            last_compaction: self.counters.last_compaction,
+            auto_compaction_error: self.counters.auto_compaction_error.clone(),
+        }
+    }
+
+    /// `None` turns automatic compaction off, which is the default
+    pub fn set_auto_compaction(&mut self, auto_compaction: Option<AutoCompaction>) {
+        self.counters.auto_compaction = auto_compaction;
+    }
+
+    /// Runs after every write, compacts when the configured threshold is crossed. The write is
+    /// already in the log by then, so a failed compaction only ends up in `Stats`. Nothing is
+    /// compacted before `load`
+    pub(crate) fn maybe_compact(&mut self) {
+        let auto = match self.counters.auto_compaction {
+            Some(auto) if self.loaded => auto,
+            _ => return,
+        };
+
+        let stats = self.stats();
+        if stats.dead_bytes >= auto.min_dead_bytes && stats.fragmentation > auto.dead_ratio {
+            self.counters.auto_compaction_error = self.compact().err().map(|e| e.to_string());
+        }
+    }
+
+    /// The value held in `old` has been superseded, its bytes are dead from now on
+    pub(crate) fn release_record(&mut self, old: Slot) {
+        self.counters.live_bytes = self.counters.live_bytes.saturating_sub(old.len);
+    }
+
+    /// Rebuilds the counters once `load` has replayed the log
+    pub(crate) fn recount(&mut self, total_records: u64) -> io::Result<()> {
+        let mut live_bytes: u64 = FILE_HEADER_LEN;
+        live_bytes += self
+            .namespaces
+            .iter()
+            .filter(|(_, id)| **id != DEFAULT_NAMESPACE)
+            .map(|(name, _)| catalog_record_len(name))
+            .sum::<u64>();
+        live_bytes += self
+            .index
+            .values()
+            .map(|index| index.live_bytes())
+            .sum::<u64>();
+
+        self.counters.total_records = total_records;
+        self.counters.live_bytes = live_bytes;
+        self.counters.log_len = self.f.size()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::AutoCompaction;
+    use crate::storage::tests::open_memory;
+    use crate::storage::{FaultyStorage, MemoryStorage, Storage};
+    use crate::{ActionKV, Options};
+
+    #[test]
+    pub fn test_stats() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        let stats = akv.stats();
+        assert_eq!(stats.file_size, 8);
+        assert_eq!(stats.fragmentation, 0.0);
+
+        // The file header takes 8 bytes, every record here is 16 bytes of header plus 2 of key
+        // and 2 of value
+        akv.insert(b"k1", b"v1").unwrap();
+        akv.insert(b"k2", b"v2").unwrap();
+        akv.insert(b"k1", b"v3").unwrap();
+        akv.delete(b"k2").unwrap();
+
+        let stats = akv.stats();
+        assert_eq!(stats.live_keys, 1);
+        assert_eq!(stats.total_records, 4);
+        assert_eq!(stats.file_size, storage.size().unwrap());
+        assert_eq!(stats.dead_bytes, 20 + 20 + 18);
+        assert!(stats.last_compaction.is_none());
+
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.stats(), stats);
+
+        akv.compact().unwrap();
+        let stats = akv.stats();
+        assert_eq!(stats.total_records, 1);
+        assert_eq!(stats.dead_bytes, 0);
+        assert_eq!(stats.file_size, 8 + 20);
+        assert!(stats.last_compaction.is_some());
+    }
+
+    #[test]
+    pub fn test_auto_compaction() {
+        let mut akv = open_memory(&MemoryStorage::new());
+        akv.set_auto_compaction(Some(AutoCompaction {
+            dead_ratio: 0.5,
+            min_dead_bytes: 100,
+        }));
+
+        for i in 0..20u8 {
+            akv.insert(b"counter", &[i; 8]).unwrap();
+        }
+
+        let stats = akv.stats();
+        assert!(stats.last_compaction.is_some());
+        assert!(stats.file_size < 20 * 31);
+        assert_eq!(akv.get(b"counter").unwrap(), Some(vec![19; 8]));
+    }
+
+    #[test]
+    pub fn test_failed_auto_compaction_keeps_the_write() {
+        let memory = MemoryStorage::new();
+        let storage = FaultyStorage::new(memory.clone());
+        let faults = storage.faults();
+        let mut akv = ActionKV::with_storage(Box::new(storage), Options::default()).unwrap();
+        akv.set_auto_compaction(Some(AutoCompaction {
+            dead_ratio: 0.5,
+            min_dead_bytes: 100,
+        }));
+
+        // Records of 16 + 7 + 8 bytes, the fifth one crosses the threshold
+        for i in 0..4u8 {
+            akv.insert(b"counter", &[i; 8]).unwrap();
+        }
+        faults.fail_reads(true);
+        akv.insert(b"counter", &[4; 8]).unwrap();
+        let stats = akv.stats();
+        assert!(stats.last_compaction.is_none());
+        assert!(stats.auto_compaction_error.unwrap().contains("injected"));
+        assert_eq!(memory.size().unwrap(), 8 + 5 * 31);
+
+        faults.clear();
+        akv.insert(b"counter", &[5; 8]).unwrap();
+        let stats = akv.stats();
+        assert!(stats.last_compaction.is_some());
+        assert_eq!(stats.auto_compaction_error, None);
+        assert_eq!(akv.get(b"counter").unwrap(), Some(vec![5; 8]));
+    }
+
+    #[test]
+    pub fn test_no_compaction_before_load() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        for i in 0..20u8 {
+            akv.insert(b"counter", &[i; 8]).unwrap();
+        }
+        let log = storage.contents();
+
+        let mut akv =
+            ActionKV::with_storage(Box::new(storage.clone()), Options::default()).unwrap();
+        assert!(akv.compact().is_err());
+        akv.set_auto_compaction(Some(AutoCompaction {
+            dead_ratio: 0.5,
+            min_dead_bytes: 100,
+        }));
+        akv.insert(b"other", b"value").unwrap();
+        assert!(akv.stats().last_compaction.is_none());
+        assert!(storage.contents().starts_with(&log));
+
+        akv.load().unwrap();
+        akv.compact().unwrap();
+        assert_eq!(akv.get(b"counter").unwrap(), Some(vec![19; 8]));
+        assert_eq!(akv.get(b"other").unwrap(), Some(b"value".to_vec()));
+    }
+}
@@ -0,0 +1,529 @@
+//! Where the log lives. `ActionKV` only needs something it can read, append to, seek in and
+//! swap for a compacted copy, so files are one backend among others.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub trait Storage: Read + Write + Seek + Send + fmt::Debug {
+    /// Current length of the log in bytes
+    fn size(&self) -> io::Result<u64>;
+
+    /// Makes everything written so far durable
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Cuts the log down to `len` bytes
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+
+    /// An empty storage of the same kind, compaction writes the new log into it
+    fn scratch(&self) -> io::Result<Box<dyn Storage>>;
+
+    /// Swaps the log for `scratch`, which must come from `scratch` on this same storage and
+    /// have been synced
+    fn replace(&mut self, scratch: Box<dyn Storage>) -> io::Result<()>;
+}
+
+#[derive(Debug)]
+pub struct FileStorage {
+    f: File,
+    path: PathBuf,
+}
+
+impl FileStorage {
+    pub fn open(path: &Path) -> io::Result<FileStorage> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        Ok(FileStorage {
+            f,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn scratch_path(&self) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(".compact");
+        PathBuf::from(name)
+    }
+}
+
+impl Read for FileStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.f.read(buf)
+    }
+}
+
+impl Write for FileStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.f.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.f.flush()
+    }
+}
+
+impl Seek for FileStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.f.seek(pos)
+    }
+}
+
+impl Storage for FileStorage {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.f.metadata()?.len())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.f.sync_all()
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.f.set_len(len)
+    }
+
+    fn scratch(&self) -> io::Result<Box<dyn Storage>> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.scratch_path())?;
+
+        Ok(Box::new(FileStorage {
+            f,
+            path: self.scratch_path(),
+        }))
+    }
+
+    fn replace(&mut self, scratch: Box<dyn Storage>) -> io::Result<()> {
+        drop(scratch);
+
+        fs::rename(self.scratch_path(), &self.path)?;
+        sync_parent(&self.path)?;
+        *self = FileStorage::open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// A rename is only durable once the directory holding the file is synced, until then a crash
+/// can bring back the old log
+#[cfg(unix)]
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+/// Directories cannot be opened as files here, the rename is as durable as it gets
+#[cfg(not(unix))]
+pub(crate) fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Log kept in memory. Clones share the same bytes, so opening a store again on a clone acts
+/// like reopening the same file
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    data: Arc<Mutex<Vec<u8>>>,
+    position: u64,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> MemoryStorage {
+        MemoryStorage {
+            data: Arc::new(Mutex::new(bytes)),
+            position: 0,
+        }
+    }
+
+    /// Copy of the whole log
+    pub fn contents(&self) -> Vec<u8> {
+        self.data().clone()
+    }
+
+    fn data(&self) -> MutexGuard<'_, Vec<u8>> {
+        // A panic while holding the lock cannot leave the bytes half updated
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Read for MemoryStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data();
+        let start = (self.position as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        drop(data);
+
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data();
+        let start = self.position as usize;
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        drop(data);
+
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => (self.data().len() as u64).checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.position)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data().len() as u64)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.data().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn scratch(&self) -> io::Result<Box<dyn Storage>> {
+        Ok(Box::new(MemoryStorage::new()))
+    }
+
+    fn replace(&mut self, mut scratch: Box<dyn Storage>) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        scratch.seek(SeekFrom::Start(0))?;
+        scratch.read_to_end(&mut bytes)?;
+
+        *self.data() = bytes;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    /// Bytes that may still be written, `None` for no limit
+    write_budget: Option<u64>,
+    /// Whether a write crossing the budget lands partially before failing
+    tear: bool,
+    fail_reads: bool,
+    fail_sync: bool,
+    error_kind: Option<io::ErrorKind>,
+}
+
+/// Shared switchboard of a `FaultyStorage`, kept by the test while the store owns the storage
+#[derive(Debug, Clone, Default)]
+pub struct Faults(Arc<Mutex<FaultState>>);
+
+impl Faults {
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Lets `bytes` more bytes through, then fails every write without writing anything
+    pub fn fail_writes_after(&self, bytes: u64) {
+        let mut state = self.state();
+        state.write_budget = Some(bytes);
+        state.tear = false;
+    }
+
+    /// Like `fail_writes_after`, but the write crossing the limit lands partially, the way a
+    /// crash in the middle of a record leaves a torn record behind
+    pub fn tear_writes_after(&self, bytes: u64) {
+        let mut state = self.state();
+        state.write_budget = Some(bytes);
+        state.tear = true;
+    }
+
+    pub fn fail_reads(&self, fail: bool) {
+        self.state().fail_reads = fail;
+    }
+
+    pub fn fail_sync(&self, fail: bool) {
+        self.state().fail_sync = fail;
+    }
+
+    /// Kind of the injected errors, `io::ErrorKind::Other` unless set
+    pub fn error_kind(&self, kind: io::ErrorKind) {
+        self.state().error_kind = Some(kind);
+    }
+
+    /// Back to a storage that never fails
+    pub fn clear(&self) {
+        *self.state() = FaultState::default();
+    }
+
+    fn error(&self, what: &str) -> io::Error {
+        let kind = self.state().error_kind.unwrap_or(io::ErrorKind::Other);
+        io::Error::new(kind, format!("injected {} failure", what))
+    }
+}
+
+/// Wraps another storage and fails on demand, see `Faults`
+#[derive(Debug)]
+pub struct FaultyStorage<S> {
+    inner: S,
+    faults: Faults,
+}
+
+impl<S: Storage> FaultyStorage<S> {
+    pub fn new(inner: S) -> FaultyStorage<S> {
+        FaultyStorage {
+            inner,
+            faults: Faults::default(),
+        }
+    }
+
+    pub fn faults(&self) -> Faults {
+        self.faults.clone()
+    }
+}
+
+impl<S: Storage> Read for FaultyStorage<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.faults.state().fail_reads {
+            return Err(self.faults.error("read"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Storage> Write for FaultyStorage<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.faults.state();
+        let budget = match state.write_budget {
+            None => {
+                drop(state);
+                return self.inner.write(buf);
+            }
+            Some(budget) => budget,
+        };
+
+        if (buf.len() as u64) <= budget {
+            let n = self.inner.write(buf)?;
+            state.write_budget = Some(budget - n as u64);
+            return Ok(n);
+        }
+
+        if state.tear && budget > 0 {
+            let n = self.inner.write(&buf[..budget as usize])?;
+            state.write_budget = Some(budget - n as u64);
+            return Ok(n);
+        }
+
+        drop(state);
+        Err(self.faults.error("write"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Storage> Seek for FaultyStorage<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<S: Storage> Storage for FaultyStorage<S> {
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.faults.state().fail_sync {
+            return Err(self.faults.error("sync"));
+        }
+        self.inner.sync()
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.inner.truncate(len)
+    }
+
+    fn scratch(&self) -> io::Result<Box<dyn Storage>> {
+        self.inner.scratch()
+    }
+
+    fn replace(&mut self, scratch: Box<dyn Storage>) -> io::Result<()> {
+        self.inner.replace(scratch)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{FaultyStorage, FileStorage, MemoryStorage, Storage};
+    use crate::{ActionKV, Options};
+    use std::io;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::{env, fs, process};
+
+    /// Tells the directories of the tests in this process apart
+    static NEXT_TEMP_DIR: AtomicU64 = AtomicU64::new(0);
+
+    /// A directory of its own for a test that needs real files. It is removed when dropped, so
+    /// also when the test fails
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+
+        pub fn join(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub fn temp_dir() -> TempDir {
+        let n = NEXT_TEMP_DIR.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("action_kv-test-{}-{}", process::id(), n));
+        // Left behind by an earlier process of the same id that got killed
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    /// Opens a store on `storage` and loads it, a clone of the same storage acts as a reopen
+    pub fn open_memory(storage: &MemoryStorage) -> ActionKV {
+        let mut akv =
+            ActionKV::with_storage(Box::new(storage.clone()), Options::default()).unwrap();
+        akv.load().unwrap();
+        akv
+    }
+
+    #[test]
+    pub fn test_memory_storage() {
+        let mut storage = MemoryStorage::new();
+        storage.write_all(b"vlad onis").unwrap();
+        storage.seek(SeekFrom::Start(5)).unwrap();
+
+        let mut rest = String::new();
+        storage.clone().read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "onis");
+
+        storage.truncate(4).unwrap();
+        assert_eq!(storage.size().unwrap(), 4);
+        assert_eq!(storage.contents(), b"vlad");
+        assert!(storage.seek(SeekFrom::Current(-10)).is_err());
+    }
+
+    #[test]
+    pub fn test_file_storage_replace() {
+        let dir = temp_dir();
+        let path = &dir.join("log");
+
+        let mut akv = ActionKV::open(path).unwrap();
+        akv.insert(b"vlad", b"onis").unwrap();
+        akv.insert(b"vlad", b"the impaler").unwrap();
+        akv.compact().unwrap();
+        akv.insert(b"after", b"compaction").unwrap();
+        drop(akv);
+
+        let scratch = FileStorage::open(path).unwrap().scratch_path();
+        assert!(!scratch.exists());
+        let mut akv = ActionKV::open(path).unwrap();
+        akv.load().unwrap();
+        assert_eq!(akv.stats().total_records, 2);
+        assert_eq!(akv.get(b"vlad").unwrap(), Some(b"the impaler".to_vec()));
+        assert_eq!(akv.get(b"after").unwrap(), Some(b"compaction".to_vec()));
+    }
+
+    #[test]
+    pub fn test_failed_write_leaves_index_untouched() {
+        let storage = FaultyStorage::new(MemoryStorage::new());
+        let faults = storage.faults();
+        let mut akv = ActionKV::with_storage(Box::new(storage), Options::default()).unwrap();
+
+        akv.insert(b"vlad", b"onis").unwrap();
+        faults.fail_writes_after(0);
+        faults.error_kind(io::ErrorKind::PermissionDenied);
+        let err = akv.insert(b"vlad", b"again").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(akv.get(b"vlad").unwrap(), Some(b"onis".to_vec()));
+
+        faults.fail_reads(true);
+        assert!(akv.get(b"vlad").is_err());
+        faults.clear();
+        assert_eq!(akv.get(b"vlad").unwrap(), Some(b"onis".to_vec()));
+    }
+
+    #[test]
+    pub fn test_torn_record_is_dropped_on_load() {
+        let memory = MemoryStorage::new();
+        let storage = FaultyStorage::new(memory.clone());
+        let faults = storage.faults();
+        let mut akv = ActionKV::with_storage(Box::new(storage), Options::default()).unwrap();
+
+        akv.insert(b"a", b"1").unwrap();
+        let committed = memory.size().unwrap();
+        faults.tear_writes_after(10);
+        assert!(akv.insert(b"b", b"2").is_err());
+        // A failed write is rolled back, only a crash in the middle of one leaves it behind
+        assert_eq!(memory.size().unwrap(), committed);
+        drop(akv);
+        let mut raw = memory.clone();
+        raw.seek(SeekFrom::End(0)).unwrap();
+        raw.write_all(&[0xAA; 10]).unwrap();
+
+        // The crash leaves the torn record behind, recovery has to cut it off before appending
+        let mut akv = open_memory(&memory);
+        assert_eq!(memory.size().unwrap(), committed);
+        akv.insert(b"c", b"3").unwrap();
+
+        let mut akv = open_memory(&memory);
+        assert_eq!(akv.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(akv.get(b"b").unwrap(), None);
+        assert_eq!(akv.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+}
@@ -0,0 +1,606 @@
+//! Anti-entropy between two replicas over TCP. The puller walks both Merkle trees from the root
+//! down, only following subtrees whose hashes differ, and then fetches just the keys of the
+//! differing leaves. Both sides need a tree of the same depth over the namespace.
+
+use crate::merkle::MerkleTree;
+use crate::{ActionKV, ByteStr, ByteString, NamespaceId};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+
+/// Frames beyond this are refused rather than allocated
+const MAX_FRAME: u32 = 64 << 20;
+/// Keys asked for in one `Fetch`
+const FETCH_BATCH: usize = 256;
+/// Bytes of keys and values put in one message, leaving `MAX_FRAME` room for the encoding
+const FRAME_BUDGET: usize = MAX_FRAME as usize / 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    /// Namespaces are named, their ids differ from one replica to the other
+    Hello {
+        namespace: String,
+        depth: u32,
+    },
+    /// Hashes of these nodes
+    Nodes(Vec<u64>),
+    /// Keys and value hashes of these leaves, the first `skip` keys of the first one left out
+    Buckets {
+        leaves: Vec<u64>,
+        skip: u64,
+    },
+    Fetch(Vec<ByteString>),
+    /// The bytes of a value too large for `Fetch` from `offset` on
+    FetchPart {
+        key: ByteString,
+        offset: u64,
+    },
+    Bye,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    Hello {
+        root: u64,
+    },
+    Nodes(Vec<u64>),
+    /// The first of the leaves asked for that fit in a message, `partial` when the last one
+    /// was cut short
+    Buckets {
+        buckets: Vec<Vec<(ByteString, u64)>>,
+        partial: bool,
+    },
+    /// Values of the first of the keys asked for that fit in a message, none when the first
+    /// value alone does not. `None` for a key deleted since its leaf was listed
+    Values(Vec<Option<ByteString>>),
+    /// Length of the whole value and its next bytes, `None` for a deleted key
+    Part(Option<(u64, ByteString)>),
+    Error(String),
+}
+
+/// What one `pull_sync` did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Nodes whose hashes were compared, the root included
+    pub nodes_compared: u64,
+    /// Leaves that differed
+    pub leaves_differing: u64,
+    pub keys_fetched: u64,
+    pub keys_deleted: u64,
+}
+
+/// Length prefixed bincode, the length is a u32
+fn write_message<W: Write, T: Serialize>(f: &mut W, message: &T) -> io::Result<()> {
+    let bytes = bincode::serialize(message).map_err(io::Error::other)?;
+    if bytes.len() > MAX_FRAME as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes is too large", bytes.len()),
+        ));
+    }
+    f.write_u32::<LittleEndian>(bytes.len() as u32)?;
+    f.write_all(&bytes)?;
+    f.flush()
+}
+
+/// `None` when the other side closed the connection between two messages
+fn read_message<R: Read, T: DeserializeOwned>(f: &mut R) -> io::Result<Option<T>> {
+    let len = match f.read_u32::<LittleEndian>() {
+        Ok(len) => len,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+
+    let mut bytes = vec![0; len as usize];
+    f.read_exact(&mut bytes)?;
+    bincode::deserialize(&bytes)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The first of `leaves` that fit in `FRAME_BUDGET`, at least one key or one empty leaf. True
+/// when the last one was cut short
+fn list_buckets(
+    tree: &MerkleTree,
+    leaves: &[u64],
+    skip: usize,
+) -> (Vec<Vec<(ByteString, u64)>>, bool) {
+    let mut buckets = Vec::new();
+    let mut size = 0;
+    for (i, leaf) in leaves.iter().enumerate() {
+        let skip = if i == 0 { skip } else { 0 };
+        let mut bucket = Vec::new();
+        for (key, hash) in tree.bucket(*leaf as usize).iter().skip(skip) {
+            size += key.len() + 16;
+            if size > FRAME_BUDGET && !(buckets.is_empty() && bucket.is_empty()) {
+                buckets.push(bucket);
+                return (buckets, true);
+            }
+            bucket.push((key.clone(), *hash));
+        }
+        size += 8;
+        buckets.push(bucket);
+    }
+    (buckets, false)
+}
+
+/// The first of `keys` that fit in `FRAME_BUDGET` along with `FETCH_BATCH` others, at least one
+fn fetch_batch(keys: &[ByteString]) -> &[ByteString] {
+    let mut size = 0;
+    let len = keys
+        .iter()
+        .take(FETCH_BATCH)
+        .take_while(|key| {
+            size += key.len() + 8;
+            size <= FRAME_BUDGET
+        })
+        .count();
+    &keys[..len.max(1)]
+}
+
+fn closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the other replica closed the connection",
+    )
+}
+
+impl ActionKV {
+    /// Answers one puller on `stream` until it is done, from the tree of the namespace it asks
+    /// for. Errors on the puller's side are sent back to it instead of ending the session
+    pub fn serve_sync(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let mut namespace = None;
+        // The value last sent in parts, kept for the parts still to come
+        let mut large = None;
+
+        while let Some(request) = read_message(&mut reader)? {
+            let response = match (request, namespace) {
+                (Request::Bye, _) => break,
+                (
+                    Request::Hello {
+                        namespace: name,
+                        depth,
+                    },
+                    _,
+                ) => match self.sync_namespace(&name, depth) {
+                    Ok(id) => {
+                        namespace = Some(id);
+                        let tree = self.merkle.get_mut(&id).unwrap();
+                        Response::Hello { root: tree.root() }
+                    }
+                    Err(e) => Response::Error(e.to_string()),
+                },
+                (_, None) => Response::Error("no namespace chosen yet".to_string()),
+                (Request::Nodes(ids), Some(id)) => {
+                    let tree = self.merkle.get_mut(&id).unwrap();
+                    match ids.iter().find(|node| **node as usize >= tree.leaves() * 2) {
+                        Some(node) => Response::Error(format!("no node {}", node)),
+                        None => Response::Nodes(
+                            ids.iter().map(|node| tree.node(*node as usize)).collect(),
+                        ),
+                    }
+                }
+                (Request::Buckets { leaves, skip }, Some(id)) => {
+                    let tree = &self.merkle[&id];
+                    match leaves.iter().find(|leaf| **leaf as usize >= tree.leaves()) {
+                        Some(leaf) => Response::Error(format!("no leaf {}", leaf)),
+                        None => {
+                            let (buckets, partial) = list_buckets(tree, &leaves, skip as usize);
+                            Response::Buckets { buckets, partial }
+                        }
+                    }
+                }
+                (Request::Fetch(keys), Some(id)) => {
+                    match self.fetch_values(id, &keys, &mut large) {
+                        Ok(values) => Response::Values(values),
+                        Err(e) => Response::Error(e.to_string()),
+                    }
+                }
+                (Request::FetchPart { key, offset }, Some(id)) => {
+                    match self.fetch_part(id, &key, offset, &mut large) {
+                        Ok(part) => Response::Part(part),
+                        Err(e) => Response::Error(e.to_string()),
+                    }
+                }
+            };
+            write_message(&mut writer, &response)?;
+        }
+
+        Ok(())
+    }
+
+    /// Values of the first of `keys` that fit in `FRAME_BUDGET`. A first value that does not is
+    /// kept in `large`, to be sent in parts
+    fn fetch_values(
+        &mut self,
+        namespace: NamespaceId,
+        keys: &[ByteString],
+        large: &mut Option<(ByteString, ByteString)>,
+    ) -> io::Result<Vec<Option<ByteString>>> {
+        let mut values = Vec::new();
+        let mut size = 0;
+        for key in keys {
+            let value = self.get_in(namespace, key)?;
+            size += value.as_ref().map_or(0, Vec::len) + 9;
+            if size > FRAME_BUDGET {
+                if let (true, Some(value)) = (values.is_empty(), value) {
+                    *large = Some((key.clone(), value));
+                }
+                break;
+            }
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    fn fetch_part(
+        &mut self,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        offset: u64,
+        large: &mut Option<(ByteString, ByteString)>,
+    ) -> io::Result<Option<(u64, ByteString)>> {
+        if large.as_ref().is_none_or(|(large_key, _)| large_key != key) {
+            *large = self
+                .get_in(namespace, key)?
+                .map(|value| (key.to_vec(), value));
+        }
+        Ok(large.as_ref().map(|(_, value)| {
+            let start = (offset as usize).min(value.len());
+            let end = (start + FRAME_BUDGET).min(value.len());
+            (value.len() as u64, value[start..end].to_vec())
+        }))
+    }
+
+    fn sync_namespace(&self, name: &str, depth: u32) -> io::Result<NamespaceId> {
+        let id = self.namespace(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("namespace {} does not exist", name),
+            )
+        })?;
+        match self.merkle.get(&id) {
+            Some(tree) if tree.depth() == depth => Ok(id),
+            Some(tree) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "merkle tree of {} is {} deep, not {}",
+                    name,
+                    tree.depth(),
+                    depth
+                ),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("namespace {} has no merkle tree", name),
+            )),
+        }
+    }
+
+    /// Makes `namespace` hold exactly what the replica on the other end of `stream` holds,
+    /// transferring only the keys that differ. Keys missing on the other end are deleted here
+    pub fn pull_sync(
+        &mut self,
+        namespace: NamespaceId,
+        stream: TcpStream,
+    ) -> io::Result<SyncReport> {
+        let name = self
+            .namespaces
+            .iter()
+            .find(|(_, id)| **id == namespace)
+            .map(|(name, _)| name.clone())
+            .ok_or_else(|| crate::namespace::unknown(namespace))?;
+        let depth = match self.merkle.get(&namespace) {
+            Some(tree) => tree.depth(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("namespace {} has no merkle tree", name),
+                ))
+            }
+        };
+
+        let mut session = Session {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        };
+        let mut report = SyncReport::default();
+
+        let remote_root = match session.call(&Request::Hello {
+            namespace: name,
+            depth,
+        })? {
+            Response::Hello { root } => root,
+            response => return Err(unexpected(response)),
+        };
+        report.nodes_compared += 1;
+        let tree = self.merkle.get_mut(&namespace).unwrap();
+        let mut differing = if remote_root == tree.root() {
+            Vec::new()
+        } else {
+            vec![1u64]
+        };
+
+        // Down one level at a time, asking only for the children of nodes that differ
+        for _ in 0..depth {
+            if differing.is_empty() {
+                break;
+            }
+            let children: Vec<u64> = differing.iter().flat_map(|n| [n * 2, n * 2 + 1]).collect();
+            let remote = match session.call(&Request::Nodes(children.clone()))? {
+                Response::Nodes(hashes) if hashes.len() == children.len() => hashes,
+                response => return Err(unexpected(response)),
+            };
+            report.nodes_compared += children.len() as u64;
+            differing = children
+                .into_iter()
+                .zip(remote)
+                .filter(|(node, hash)| tree.node(*node as usize) != *hash)
+                .map(|(node, _)| node)
+                .collect();
+        }
+
+        let first_leaf = tree.leaves() as u64;
+        let leaves: Vec<u64> = differing.iter().map(|node| node - first_leaf).collect();
+        report.leaves_differing = leaves.len() as u64;
+        // Listed over as many messages as it takes, a leaf cut short is asked for again from
+        // the key it stopped at
+        let mut buckets: Vec<Vec<(ByteString, u64)>> = Vec::new();
+        let mut partial = false;
+        while buckets.len() < leaves.len() || partial {
+            let (next, skip) = match (partial, buckets.last()) {
+                (true, Some(bucket)) => (buckets.len() - 1, bucket.len()),
+                _ => (buckets.len(), 0),
+            };
+            let request = Request::Buckets {
+                leaves: leaves[next..].to_vec(),
+                skip: skip as u64,
+            };
+            match session.call(&request)? {
+                Response::Buckets {
+                    buckets: listed,
+                    partial: cut,
+                } if !listed.is_empty()
+                    && listed.len() <= leaves.len() - next
+                    && !(cut && listed.len() == 1 && listed[0].is_empty()) =>
+                {
+                    let mut listed = listed.into_iter();
+                    if partial {
+                        buckets.last_mut().unwrap().extend(listed.next().unwrap());
+                    }
+                    buckets.extend(listed);
+                    partial = cut;
+                }
+                response => return Err(unexpected(response)),
+            }
+        }
+
+        let mut fetch = Vec::new();
+        let mut delete = Vec::new();
+        for (leaf, remote) in leaves.into_iter().zip(buckets) {
+            let local = tree.bucket(leaf as usize);
+            for (key, hash) in &remote {
+                if local.get(key) != Some(hash) {
+                    fetch.push(key.clone());
+                }
+            }
+            let remote_keys: HashSet<&ByteString> = remote.iter().map(|(key, _)| key).collect();
+            delete.extend(
+                local
+                    .keys()
+                    .filter(|key| !remote_keys.contains(key))
+                    .cloned(),
+            );
+        }
+
+        let mut pending = &fetch[..];
+        while !pending.is_empty() {
+            let keys = fetch_batch(pending);
+            let values = match session.call(&Request::Fetch(keys.to_vec()))? {
+                Response::Values(values) if values.is_empty() => {
+                    vec![session.fetch_large(&keys[0])?]
+                }
+                Response::Values(values) if values.len() <= keys.len() => values,
+                response => return Err(unexpected(response)),
+            };
+            pending = &pending[values.len()..];
+            for (key, value) in keys.iter().zip(values) {
+                match value {
+                    Some(value) => {
+                        self.insert_in(namespace, key, &value)?;
+                        report.keys_fetched += 1;
+                    }
+                    None => delete.push(key.clone()),
+                }
+            }
+        }
+        for key in delete {
+            if self.contains_key_in(namespace, &key)? {
+                self.delete_in(namespace, &key)?;
+                report.keys_deleted += 1;
+            }
+        }
+
+        write_message(&mut session.writer, &Request::Bye)?;
+        Ok(report)
+    }
+}
+
+struct Session {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Session {
+    fn call(&mut self, request: &Request) -> io::Result<Response> {
+        write_message(&mut self.writer, request)?;
+        match read_message(&mut self.reader)? {
+            None => Err(closed()),
+            Some(Response::Error(e)) => Err(io::Error::other(e)),
+            Some(response) => Ok(response),
+        }
+    }
+
+    /// A value too large for one message, asked for part by part
+    fn fetch_large(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        let mut value = Vec::new();
+        loop {
+            let request = Request::FetchPart {
+                key: key.to_vec(),
+                offset: value.len() as u64,
+            };
+            match self.call(&request)? {
+                Response::Part(None) => return Ok(None),
+                Response::Part(Some((len, bytes)))
+                    if !bytes.is_empty() || value.len() as u64 >= len =>
+                {
+                    value.extend(bytes);
+                    if value.len() as u64 >= len {
+                        return Ok(Some(value));
+                    }
+                }
+                response => return Err(unexpected(response)),
+            }
+        }
+    }
+}
+
+fn unexpected(response: Response) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response {:?}", response),
+    )
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{write_message, SyncReport, MAX_FRAME};
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+    use crate::ActionKV;
+    use std::io;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// Serves `remote` for one puller, handing it back once the session is over
+    fn serve_once(mut remote: ActionKV) -> (TcpStream, thread::JoinHandle<ActionKV>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            remote.serve_sync(stream).unwrap();
+            remote
+        });
+        (TcpStream::connect(addr).unwrap(), server)
+    }
+
+    #[test]
+    pub fn test_pull_transfers_only_differences() {
+        let mut remote = open_memory(&MemoryStorage::new());
+        let mut local = open_memory(&MemoryStorage::new());
+        for i in 0..1000u32 {
+            let key = format!("key:{}", i);
+            remote.insert(key.as_bytes(), b"same").unwrap();
+            local.insert(key.as_bytes(), b"same").unwrap();
+        }
+        remote.insert(b"key:7", b"newer").unwrap();
+        remote.insert(b"only remote", b"1").unwrap();
+        local.insert(b"only local", b"1").unwrap();
+        remote.enable_merkle_tree(8).unwrap();
+        local.enable_merkle_tree(8).unwrap();
+
+        let (stream, server) = serve_once(remote);
+        let report = local.pull_sync(0, stream).unwrap();
+        let mut remote = server.join().unwrap();
+        assert_eq!(report.keys_fetched, 2);
+        assert_eq!(report.keys_deleted, 1);
+        assert!(report.leaves_differing <= 3);
+        assert!(report.nodes_compared < 100);
+
+        assert_eq!(local.get(b"key:7").unwrap(), Some(b"newer".to_vec()));
+        assert_eq!(local.get(b"only remote").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(local.get(b"only local").unwrap(), None);
+        let root = remote.merkle_tree().unwrap().root();
+        assert_eq!(local.merkle_tree().unwrap().root(), root);
+
+        // In sync already, only the roots are compared
+        let (stream, server) = serve_once(remote);
+        let report = local.pull_sync(0, stream).unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            report,
+            SyncReport {
+                nodes_compared: 1,
+                ..SyncReport::default()
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_pull_splits_what_does_not_fit_in_a_frame() {
+        let mut remote = open_memory(&MemoryStorage::new());
+        // More keys than fit in one frame, all in the single leaf, and values larger than one
+        for i in 0..600u32 {
+            let key = format!("{:061440}", i);
+            remote.insert(key.as_bytes(), b"small").unwrap();
+        }
+        for i in 0..2u8 {
+            remote.insert(&[i], &vec![i; 20 << 20]).unwrap();
+        }
+        remote
+            .insert(b"larger than a frame", &vec![7; (MAX_FRAME + 1) as usize])
+            .unwrap();
+        remote.enable_merkle_tree(0).unwrap();
+        let mut local = open_memory(&MemoryStorage::new());
+        local.enable_merkle_tree(0).unwrap();
+
+        let (stream, server) = serve_once(remote);
+        let report = local.pull_sync(0, stream).unwrap();
+        let mut remote = server.join().unwrap();
+        assert_eq!(report.keys_fetched, 603);
+        assert_eq!(local.get(&[1]).unwrap(), Some(vec![1; 20 << 20]));
+        let value = local.get(b"larger than a frame").unwrap().unwrap();
+        assert_eq!(value.len(), (MAX_FRAME + 1) as usize);
+        let root = remote.merkle_tree().unwrap().root();
+        assert_eq!(local.merkle_tree().unwrap().root(), root);
+    }
+
+    #[test]
+    pub fn test_oversized_frame_is_refused() {
+        let mut out = Vec::new();
+        let err = write_message(&mut out, &vec![0u8; MAX_FRAME as usize]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    pub fn test_pull_needs_matching_trees() {
+        let mut remote = open_memory(&MemoryStorage::new());
+        remote.enable_merkle_tree(4).unwrap();
+        let mut local = open_memory(&MemoryStorage::new());
+        let users = local.create_namespace("users").unwrap();
+        local.enable_merkle_tree_in(users, 4).unwrap();
+        local.enable_merkle_tree(6).unwrap();
+
+        let (stream, server) = serve_once(remote);
+        let err = local.pull_sync(users, stream).unwrap_err();
+        assert!(err.to_string().contains("users does not exist"), "{}", err);
+        let remote = server.join().unwrap();
+
+        let (stream, server) = serve_once(remote);
+        assert!(local.pull_sync(0, stream).is_err());
+        server.join().unwrap();
+    }
+}
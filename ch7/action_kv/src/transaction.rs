@@ -0,0 +1,295 @@
+//! Optimistic transactions. Reads remember the offset each key had, writes are buffered, and a
+//! commit only goes through when none of the keys read has moved since and the log has not been
+//! compacted since the transaction began. The writes of a commit
+//! land in the log as one record, so a crash leaves either all or none of them behind.
+
+use crate::namespace::CATALOG_NAMESPACE;
+use crate::{
+    record_len, ActionKV, ByteStr, ByteString, ChecksumAlgorithm, KeyValuePair, Limits,
+    NamespaceId, DEFAULT_NAMESPACE, HEADER_LEN,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::Cursor;
+
+/// Records of this namespace hold the writes of a committed transaction. Their value is made
+/// of complete records, one per write, which the index points into directly
+pub(crate) const TRANSACTION_NAMESPACE: NamespaceId = CATALOG_NAMESPACE - 1;
+
+/// Splits the record of a committed transaction found at `position` into its writes, each with
+/// the offset it has in the log
+pub(crate) fn split_batch(
+    position: u64,
+    batch: KeyValuePair,
+    algorithm: ChecksumAlgorithm,
+    limits: Limits,
+) -> io::Result<Vec<(u64, KeyValuePair)>> {
+    let mut offset = position + HEADER_LEN + batch.key.len() as u64;
+    let mut data = batch.value.as_slice();
+    let mut writes = Vec::new();
+
+    while !data.is_empty() {
+        let kv = ActionKV::process_record(&mut data, algorithm, limits)?;
+        let len = record_len(kv.key.len(), kv.value.len());
+        writes.push((offset, kv));
+        offset += len;
+    }
+
+    Ok(writes)
+}
+
+/// Carried inside the `io::Error` returned by `ActionKV::commit` when a key read by the
+/// transaction was written by someone else before the commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub namespace: NamespaceId,
+    pub key: ByteString,
+}
+
+impl Conflict {
+    pub fn from_io(e: &io::Error) -> Option<&Conflict> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "key {} of namespace {} changed since the transaction read it",
+            String::from_utf8_lossy(&self.key),
+            self.namespace
+        )
+    }
+}
+
+impl Error for Conflict {}
+
+/// Started with `ActionKV::begin`. It does not borrow the store, so other writes can happen
+/// while it is open, and finished with `ActionKV::commit`. Dropping it discards it
+#[derive(Debug, Default)]
+pub struct Transaction {
+    /// Offset of every key when it was first read, `None` when it did not exist
+    reads: HashMap<(NamespaceId, ByteString), Option<u64>>,
+    /// Latest buffered write of every key, an empty value deletes it
+    writes: BTreeMap<(NamespaceId, ByteString), ByteString>,
+    /// Compactions of the store when the transaction began. Compaction rewrites the log from the
+    /// start, so a key can be back at the offset it was read at after changing in between
+    compactions: u64,
+}
+
+impl Transaction {
+    pub fn get(&mut self, store: &mut ActionKV, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        self.get_in(store, DEFAULT_NAMESPACE, key)
+    }
+
+    /// Sees the writes buffered by this transaction, otherwise reads from `store` and remembers
+    /// which version of the key it saw
+    pub fn get_in(
+        &mut self,
+        store: &mut ActionKV,
+        namespace: NamespaceId,
+        key: &ByteStr,
+    ) -> io::Result<Option<ByteString>> {
+        let read_key = (namespace, key.to_vec());
+        if let Some(value) = self.writes.get(&read_key) {
+            return Ok(if value.is_empty() {
+                None
+            } else {
+                Some(value.clone())
+            });
+        }
+
+        let version = store.namespace_index(namespace)?.get(key)?;
+        self.reads.entry(read_key).or_insert(version);
+        match version {
+            None => Ok(None),
+            Some(position) => Ok(Some(store.value_at(namespace, position)?)),
+        }
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, val: &ByteStr) {
+        self.insert_in(DEFAULT_NAMESPACE, key, val)
+    }
+
+    /// An empty `val` deletes the key, like it does for `ActionKV::insert`
+    pub fn insert_in(&mut self, namespace: NamespaceId, key: &ByteStr, val: &ByteStr) {
+        self.writes.insert((namespace, key.to_vec()), val.to_vec());
+    }
+
+    pub fn delete(&mut self, key: &ByteStr) {
+        self.delete_in(DEFAULT_NAMESPACE, key)
+    }
+
+    pub fn delete_in(&mut self, namespace: NamespaceId, key: &ByteStr) {
+        self.insert_in(namespace, key, b"")
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+impl ActionKV {
+    pub fn begin(&self) -> Transaction {
+        Transaction {
+            compactions: self.counters.compactions,
+            ..Transaction::default()
+        }
+    }
+
+    /// Applies the writes of `tx` atomically. Fails with a `Conflict` when a key it read has
+    /// been written since. A compaction since `begin` makes a transaction that read anything
+    /// conflict too, as the offsets it read may have been reused. All the writes together have
+    /// to fit within `Limits::max_value_len`
+    pub fn commit(&mut self, tx: Transaction) -> io::Result<()> {
+        if tx.compactions != self.counters.compactions {
+            if let Some((namespace, key)) = tx.reads.keys().min() {
+                return Err(io::Error::other(Conflict {
+                    namespace: *namespace,
+                    key: key.clone(),
+                }));
+            }
+        }
+        for ((namespace, key), version) in &tx.reads {
+            let current = match self.index.get(namespace) {
+                None => None,
+                Some(index) => index.get(key)?,
+            };
+            if current != *version {
+                return Err(io::Error::other(Conflict {
+                    namespace: *namespace,
+                    key: key.clone(),
+                }));
+            }
+        }
+        if tx.writes.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = Cursor::new(Vec::new());
+        let mut offsets = Vec::with_capacity(tx.writes.len());
+        for ((namespace, key), val) in &tx.writes {
+            self.namespace_index(*namespace)?;
+            self.limits.check_write(key, val)?;
+            offsets.push(ActionKV::write_record(
+                &mut batch,
+                self.checksum,
+                *namespace,
+                key,
+                val,
+            )?);
+        }
+
+        let position = self.append(TRANSACTION_NAMESPACE, b"", &batch.into_inner())?;
+        let base = position + HEADER_LEN;
+        for (((namespace, key), val), offset) in tx.writes.into_iter().zip(offsets) {
+            if val.is_empty() {
+                self.index_delete(namespace, &key, base + offset)?;
+            } else {
+                self.index_insert(namespace, &key, &val, base + offset)?;
+            }
+        }
+
+        self.maybe_compact();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::Conflict;
+    use crate::storage::tests::open_memory;
+    use crate::storage::{FaultyStorage, MemoryStorage, Storage};
+    use crate::{ActionKV, Options};
+
+    #[test]
+    pub fn test_commit_and_conflict() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        akv.insert(b"alice", b"100").unwrap();
+        akv.insert(b"bob", b"50").unwrap();
+
+        // Move 30 from alice to bob
+        let mut tx = akv.begin();
+        let alice = tx.get(&mut akv, b"alice").unwrap().unwrap();
+        let bob = tx.get(&mut akv, b"bob").unwrap().unwrap();
+        assert_eq!((alice, bob), (b"100".to_vec(), b"50".to_vec()));
+        tx.insert(b"alice", b"70");
+        tx.insert(b"bob", b"80");
+        tx.delete(b"carol");
+        assert_eq!(tx.get(&mut akv, b"bob").unwrap(), Some(b"80".to_vec()));
+        akv.commit(tx).unwrap();
+        assert_eq!(akv.get(b"alice").unwrap(), Some(b"70".to_vec()));
+
+        // Someone else writes a key the transaction has read
+        let mut tx = akv.begin();
+        tx.get(&mut akv, b"bob").unwrap();
+        assert_eq!(tx.get(&mut akv, b"carol").unwrap(), None);
+        tx.insert(b"alice", b"0");
+        akv.insert(b"carol", b"1").unwrap();
+        let err = akv.commit(tx).unwrap_err();
+        assert_eq!(
+            Conflict::from_io(&err),
+            Some(&Conflict {
+                namespace: 0,
+                key: b"carol".to_vec(),
+            })
+        );
+        assert_eq!(akv.get(b"alice").unwrap(), Some(b"70".to_vec()));
+
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.get(b"alice").unwrap(), Some(b"70".to_vec()));
+        assert_eq!(akv.get(b"bob").unwrap(), Some(b"80".to_vec()));
+        assert_eq!(akv.stats().live_keys, 3);
+        akv.compact().unwrap();
+        assert_eq!(akv.get(b"bob").unwrap(), Some(b"80".to_vec()));
+    }
+
+    #[test]
+    pub fn test_compaction_conflicts_even_at_the_same_offset() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        akv.insert(b"alice", b"100").unwrap();
+
+        let mut tx = akv.begin();
+        tx.get(&mut akv, b"alice").unwrap();
+        tx.insert(b"bob", b"1");
+        akv.insert(b"alice", b"999").unwrap();
+        // The only record left lands where the one read by the transaction was
+        akv.compact().unwrap();
+        let err = akv.commit(tx).unwrap_err();
+        assert!(Conflict::from_io(&err).is_some());
+        assert_eq!(akv.get(b"bob").unwrap(), None);
+
+        // Blind writes do not care
+        let mut tx = akv.begin();
+        tx.insert(b"bob", b"1");
+        akv.compact().unwrap();
+        akv.commit(tx).unwrap();
+        assert_eq!(akv.get(b"bob").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    pub fn test_torn_commit_is_all_or_nothing() {
+        let memory = MemoryStorage::new();
+        let storage = FaultyStorage::new(memory.clone());
+        let faults = storage.faults();
+        let mut akv = ActionKV::with_storage(Box::new(storage), Options::default()).unwrap();
+        akv.insert(b"a", b"1").unwrap();
+        let committed = memory.size().unwrap();
+
+        let mut tx = akv.begin();
+        tx.insert(b"a", b"2");
+        tx.insert(b"b", b"2");
+        faults.tear_writes_after(40);
+        assert!(akv.commit(tx).is_err());
+
+        let mut akv = open_memory(&memory);
+        assert_eq!(memory.size().unwrap(), committed);
+        assert_eq!(akv.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(akv.get(b"b").unwrap(), None);
+    }
+}
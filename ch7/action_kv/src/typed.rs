@@ -0,0 +1,164 @@
+use crate::codec::{Bincode, Codec};
+use crate::{ActionKV, Keys};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+use std::marker::PhantomData;
+use std::path::Path;
+
+/// Type safe view over an `ActionKV` store, keys and values are encoded with the codec `C`
+#[derive(Debug)]
+pub struct TypedStore<K, V, C = Bincode> {
+    store: ActionKV,
+    _types: PhantomData<(K, V, C)>,
+}
+
+impl<K, V, C> TypedStore<K, V, C>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Wraps a store that has already been loaded
+    pub fn new(store: ActionKV) -> TypedStore<K, V, C> {
+        TypedStore {
+            store,
+            _types: PhantomData,
+        }
+    }
+
+    pub fn open(path: &Path) -> io::Result<TypedStore<K, V, C>> {
+        let mut store = ActionKV::open(path)?;
+        store.load()?;
+        Ok(TypedStore::new(store))
+    }
+
+    pub fn into_inner(self) -> ActionKV {
+        self.store
+    }
+
+    pub fn get(&mut self, key: &K) -> io::Result<Option<V>> {
+        let key = C::encode(key)?;
+        match self.store.get(&key)? {
+            None => Ok(None),
+            Some(value) => Ok(Some(C::decode(&value)?)),
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> io::Result<bool> {
+        let key = C::encode(key)?;
+        self.store.contains_key(&key)
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> io::Result<()> {
+        let key = C::encode(key)?;
+        let value = C::encode(value)?;
+        if value.is_empty() {
+            // An empty record is a tombstone, see `ActionKV::delete`
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "value encodes to zero bytes",
+            ));
+        }
+
+        self.store.insert(&key, &value)
+    }
+
+    pub fn delete(&mut self, key: &K) -> io::Result<()> {
+        let key = C::encode(key)?;
+        self.store.delete(&key)
+    }
+
+    /// Iterates over every live entry, values are read from disk lazily
+    pub fn iter(&mut self) -> Iter<'_, K, V, C> {
+        Iter {
+            keys: self.store.keys(),
+            store: &mut self.store,
+            _types: PhantomData,
+        }
+    }
+}
+
+pub struct Iter<'a, K, V, C> {
+    store: &'a mut ActionKV,
+    keys: Keys,
+    _types: PhantomData<(K, V, C)>,
+}
+
+impl<'a, K, V, C> Iterator for Iter<'a, K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    type Item = io::Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let raw_key = match self.keys.next()? {
+            Ok(raw_key) => raw_key,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let entry = self.store.get(&raw_key).and_then(|value| {
+            let value = value.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "key vanished during iteration")
+            })?;
+            Ok((C::decode(&raw_key)?, C::decode(&value)?))
+        });
+
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::TypedStore;
+    use crate::codec::{Bincode, Json};
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u8,
+    }
+
+    #[test]
+    pub fn test_typed_insert_get_delete() {
+        let storage = MemoryStorage::new();
+        let vlad = User {
+            name: String::from("vlad"),
+            age: 30,
+        };
+
+        let mut store: TypedStore<u64, User, Bincode> = TypedStore::new(open_memory(&storage));
+        store.insert(&1, &vlad).unwrap();
+        assert_eq!(store.get(&1).unwrap(), Some(vlad.clone()));
+        assert_eq!(store.get(&2).unwrap(), None);
+
+        store.delete(&1).unwrap();
+        assert_eq!(store.get(&1).unwrap(), None);
+        drop(store);
+
+        let mut store: TypedStore<u64, User, Bincode> = TypedStore::new(open_memory(&storage));
+        assert!(!store.contains_key(&1).unwrap());
+        assert_eq!(store.get(&1).unwrap(), None);
+    }
+
+    #[test]
+    pub fn test_typed_iter_json() {
+        let mut store: TypedStore<String, Vec<u32>, Json> =
+            TypedStore::new(open_memory(&MemoryStorage::new()));
+        store.insert(&String::from("a"), &vec![1, 2]).unwrap();
+        store.insert(&String::from("b"), &vec![3]).unwrap();
+        store.insert(&String::from("a"), &vec![4]).unwrap();
+
+        let mut entries: Vec<(String, Vec<u32>)> = store.iter().map(|e| e.unwrap()).collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![(String::from("a"), vec![4]), (String::from("b"), vec![3])]
+        );
+    }
+}
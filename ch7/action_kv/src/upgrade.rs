@@ -0,0 +1,146 @@
+//! Logs written by the first ActionKV, before namespaces and the file header. Their records are
+//! checksum(u32, CRC-32 of key and value), key_len(u32), val_len(u32), key and value. Opening
+//! such a log rewrites it in the current format, every key going to the default namespace.
+
+use crate::storage::Storage;
+use crate::{ActionKV, ByteStr, ChecksumAlgorithm, DEFAULT_NAMESPACE, MAGIC};
+use byteorder::{LittleEndian, ReadBytesExt};
+use crc::crc32;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom};
+
+/// checksum, key_len and val_len, all u32
+const BASELINE_HEADER_LEN: u64 = 12;
+
+/// Hands every key and value of a baseline log to `visit` and returns how many there were.
+/// `None` when `f` does not hold one, because a record does not match its checksum. A torn
+/// record at the end is left out, the way `load` drops it
+fn scan_baseline<F>(f: &mut Box<dyn Storage>, mut visit: F) -> io::Result<Option<u64>>
+where
+    F: FnMut(&ByteStr, &ByteStr) -> io::Result<()>,
+{
+    let log_len = f.size()?;
+    let mut f = BufReader::new(f);
+    f.seek(SeekFrom::Start(0))?;
+
+    let mut offset = 0;
+    let mut records = 0;
+    while offset + BASELINE_HEADER_LEN <= log_len {
+        let checksum = f.read_u32::<LittleEndian>()?;
+        let key_len = f.read_u32::<LittleEndian>()? as usize;
+        let val_len = f.read_u32::<LittleEndian>()? as usize;
+        let end = offset + BASELINE_HEADER_LEN + (key_len + val_len) as u64;
+        if end > log_len {
+            break;
+        }
+
+        let mut data = vec![0; key_len + val_len];
+        f.read_exact(&mut data)?;
+        if crc32::checksum_ieee(&data) != checksum {
+            return Ok(None);
+        }
+        visit(&data[..key_len], &data[key_len..])?;
+        records += 1;
+        offset = end;
+    }
+
+    Ok(Some(records))
+}
+
+/// Rewrites `f` in the current format when it holds a baseline log, checksummed with
+/// `checksum`. False, and `f` left alone, for anything else
+pub(crate) fn upgrade_baseline_log(
+    f: &mut Box<dyn Storage>,
+    checksum: ChecksumAlgorithm,
+) -> io::Result<bool> {
+    let mut magic = [0; 3];
+    f.seek(SeekFrom::Start(0))?;
+    if f.read_exact(&mut magic).is_ok() && &magic == MAGIC {
+        return Ok(false);
+    }
+    match scan_baseline(f, |_, _| Ok(()))? {
+        Some(records) if records > 0 => {}
+        _ => return Ok(false),
+    }
+
+    let mut out = BufWriter::new(f.scratch()?);
+    ActionKV::write_file_header(&mut out, checksum)?;
+    scan_baseline(f, |key, value| {
+        ActionKV::write_record(&mut out, checksum, DEFAULT_NAMESPACE, key, value).map(|_| ())
+    })?;
+    let mut scratch = out.into_inner().map_err(|e| e.into_error())?;
+    scratch.sync()?;
+    f.replace(scratch)?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::storage::tests::open_memory;
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::{ActionKV, ChecksumAlgorithm, Options, FILE_HEADER_LEN};
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use crc::crc32;
+    use std::io;
+
+    /// A record the way the first ActionKV wrote it
+    fn baseline_record(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let data = [key, value].concat();
+        let mut record = Vec::new();
+        record
+            .write_u32::<LittleEndian>(crc32::checksum_ieee(&data))
+            .unwrap();
+        record.write_u32::<LittleEndian>(key.len() as u32).unwrap();
+        record
+            .write_u32::<LittleEndian>(value.len() as u32)
+            .unwrap();
+        record.extend_from_slice(&data);
+        record
+    }
+
+    #[test]
+    pub fn test_baseline_log_is_upgraded() {
+        let mut log = baseline_record(b"vlad", b"onis");
+        log.extend(baseline_record(b"test", b"data"));
+        log.extend(baseline_record(b"vlad", b"the impaler"));
+        // Torn by a crash in the middle of the last write
+        log.extend(&baseline_record(b"torn", b"record")[..14]);
+        let storage = MemoryStorage::from_bytes(log);
+
+        let options = Options {
+            checksum: ChecksumAlgorithm::XxHash32,
+            ..Options::default()
+        };
+        let mut akv = ActionKV::with_storage(Box::new(storage.clone()), options).unwrap();
+        akv.load().unwrap();
+        assert_eq!(akv.checksum_algorithm(), ChecksumAlgorithm::XxHash32);
+        assert_eq!(akv.get(b"vlad").unwrap(), Some(b"the impaler".to_vec()));
+        assert_eq!(akv.get(b"test").unwrap(), Some(b"data".to_vec()));
+        assert_eq!(akv.get(b"torn").unwrap(), None);
+        assert_eq!(akv.stats().total_records, 3);
+        assert_eq!(
+            storage.size().unwrap(),
+            FILE_HEADER_LEN + 16 * 3 + 8 + 8 + 15
+        );
+
+        // Upgraded once, the log opens like any other
+        akv.insert(b"new", b"record").unwrap();
+        let mut akv = open_memory(&storage);
+        assert_eq!(akv.get(b"vlad").unwrap(), Some(b"the impaler".to_vec()));
+        assert_eq!(akv.get(b"new").unwrap(), Some(b"record".to_vec()));
+    }
+
+    #[test]
+    pub fn test_unknown_log_is_rejected() {
+        let mut log = baseline_record(b"vlad", b"onis");
+        log[0] ^= 0xff;
+        let storage = MemoryStorage::from_bytes(log.clone());
+
+        let err =
+            ActionKV::with_storage(Box::new(storage.clone()), Options::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("not an ActionKV log"));
+        assert_eq!(storage.contents(), log);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::merge::{Operand, MERGE_NAMESPACE};
+use crate::transaction::{split_batch, TRANSACTION_NAMESPACE};
+use crate::{
+    record_len, ActionKV, ByteStr, ByteString, NamespaceId, DEFAULT_NAMESPACE, FILE_HEADER_LEN,
+};
+use std::io;
+use std::io::{Seek, SeekFrom};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert(ByteString),
+    Delete,
+    /// The operand of a `merge`, the value it leads to is only known once it is read
+    Merge(ByteString),
+}
+
+/// One write as it was appended to the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// Where the record starts in the log
+    pub offset: u64,
+    /// Where the next record starts, pass it to `watch_from` to resume after this event
+    pub next_offset: u64,
+    pub namespace: NamespaceId,
+    pub key: ByteString,
+    pub kind: ChangeKind,
+}
+
+impl ChangeEvent {
+    fn new(offset: u64, namespace: NamespaceId, key: &ByteStr, value: &ByteStr) -> ChangeEvent {
+        let kind = if value.is_empty() {
+            ChangeKind::Delete
+        } else {
+            ChangeKind::Insert(value.to_vec())
+        };
+
+        ChangeEvent {
+            offset,
+            next_offset: offset + record_len(key.len(), value.len()),
+            namespace,
+            key: key.to_vec(),
+            kind,
+        }
+    }
+
+    /// `record_len` is the size of the operand record, which holds more than the operand
+    fn merge(
+        offset: u64,
+        record_len: u64,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        operand: &ByteStr,
+    ) -> ChangeEvent {
+        ChangeEvent {
+            offset,
+            next_offset: offset + record_len,
+            namespace,
+            key: key.to_vec(),
+            kind: ChangeKind::Merge(operand.to_vec()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Watcher {
+    namespace: NamespaceId,
+    prefix: ByteString,
+    sender: Sender<ChangeEvent>,
+}
+
+impl Watcher {
+    fn matches(&self, namespace: NamespaceId, key: &ByteStr) -> bool {
+        self.namespace == namespace && key.starts_with(&self.prefix)
+    }
+}
+
+impl ActionKV {
+    /// Streams every later insert and delete of a default namespace key starting with `prefix`
+    pub fn watch(&mut self, prefix: &ByteStr) -> Receiver<ChangeEvent> {
+        self.watch_in(DEFAULT_NAMESPACE, prefix)
+    }
+
+    pub fn watch_in(&mut self, namespace: NamespaceId, prefix: &ByteStr) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.push(Watcher {
+            namespace,
+            prefix: prefix.to_vec(),
+            sender,
+        });
+        receiver
+    }
+
+    /// Like `watch_in`, but first replays the matching records already in the log from `offset`
+    /// on. `offset` has to be a record boundary, the `next_offset` of the last event a consumer
+    /// handled or 0. Compaction rewrites the log, offsets taken before it are no longer valid
+    pub fn watch_from(
+        &mut self,
+        namespace: NamespaceId,
+        prefix: &ByteStr,
+        offset: u64,
+    ) -> io::Result<Receiver<ChangeEvent>> {
+        let (sender, receiver) = mpsc::channel();
+        let watcher = Watcher {
+            namespace,
+            prefix: prefix.to_vec(),
+            sender,
+        };
+
+        let mut f = io::BufReader::new(&mut self.f);
+        let end = f.seek(SeekFrom::End(0))?;
+        if offset > end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("offset {} is past the end of the log ({})", offset, end),
+            ));
+        }
+
+        let mut position = f.seek(SeekFrom::Start(offset.max(FILE_HEADER_LEN)))?;
+        while position < end {
+            let kv = ActionKV::process_record(&mut f, self.checksum, self.limits)?;
+            let next = position + record_len(kv.key.len(), kv.value.len());
+
+            // The writes of a transaction are complete records themselves, resuming from one of
+            // them reads the rest of the transaction as plain records
+            let records = if kv.namespace == TRANSACTION_NAMESPACE {
+                split_batch(position, kv, self.checksum, self.limits)?
+            } else {
+                vec![(position, kv)]
+            };
+            for (position, kv) in records {
+                let event = if kv.namespace == MERGE_NAMESPACE {
+                    let len = record_len(kv.key.len(), kv.value.len());
+                    let operand = Operand::decode(&kv.value)?;
+                    ChangeEvent::merge(position, len, operand.namespace, &kv.key, &operand.operand)
+                } else {
+                    ChangeEvent::new(position, kv.namespace, &kv.key, &kv.value)
+                };
+                if watcher.matches(event.namespace, &event.key) {
+                    // The receiver is still in our hands, sending cannot fail
+                    let _ = watcher.sender.send(event);
+                }
+            }
+            position = next;
+        }
+
+        self.watchers.push(watcher);
+        Ok(receiver)
+    }
+
+    /// Watchers whose receiver was dropped are forgotten on the next matching write
+    pub(crate) fn notify_watchers(
+        &mut self,
+        offset: u64,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        value: &ByteStr,
+    ) {
+        if self.watchers.is_empty() {
+            return;
+        }
+        self.broadcast(ChangeEvent::new(offset, namespace, key, value));
+    }
+
+    /// `value_len` is the size of the value of the operand record
+    pub(crate) fn notify_watchers_of_merge(
+        &mut self,
+        offset: u64,
+        namespace: NamespaceId,
+        key: &ByteStr,
+        operand: &ByteStr,
+        value_len: usize,
+    ) {
+        if self.watchers.is_empty() {
+            return;
+        }
+        let len = record_len(key.len(), value_len);
+        self.broadcast(ChangeEvent::merge(offset, len, namespace, key, operand));
+    }
+
+    fn broadcast(&mut self, event: ChangeEvent) {
+        self.watchers.retain(|watcher| {
+            !watcher.matches(event.namespace, &event.key)
+                || watcher.sender.send(event.clone()).is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{ChangeEvent, ChangeKind};
+    use crate::storage::tests::open_memory;
+    use crate::storage::MemoryStorage;
+    use crate::DEFAULT_NAMESPACE;
+
+    #[test]
+    pub fn test_watch_prefix() {
+        let mut akv = open_memory(&MemoryStorage::new());
+        let users = akv.watch(b"user/");
+
+        akv.insert(b"user/1", b"vlad").unwrap();
+        akv.insert(b"order/1", b"book").unwrap();
+        akv.delete(b"user/1").unwrap();
+
+        let events: Vec<ChangeEvent> = users.try_iter().collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].key, b"user/1");
+        assert_eq!(events[0].kind, ChangeKind::Insert(b"vlad".to_vec()));
+        assert_eq!(events[1].kind, ChangeKind::Delete);
+        assert!(events[0].next_offset < events[1].offset);
+
+        drop(users);
+        akv.insert(b"user/2", b"ana").unwrap();
+        assert!(akv.watchers.is_empty());
+    }
+
+    #[test]
+    pub fn test_watch_resumes_from_offset() {
+        let storage = MemoryStorage::new();
+        let mut akv = open_memory(&storage);
+        let events = akv.watch(b"");
+        akv.insert(b"a", b"1").unwrap();
+        akv.insert(b"b", b"2").unwrap();
+        let seen = events.recv().unwrap();
+        drop(events);
+
+        // The consumer restarts having handled only the first event
+        akv.insert(b"c", b"3").unwrap();
+        let mut akv = open_memory(&storage);
+        let events = akv
+            .watch_from(DEFAULT_NAMESPACE, b"", seen.next_offset)
+            .unwrap();
+        akv.insert(b"d", b"4").unwrap();
+
+        let keys: Vec<Vec<u8>> = events.try_iter().map(|event| event.key).collect();
+        assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+
+        assert!(akv.watch_from(DEFAULT_NAMESPACE, b"", 1 << 20).is_err());
+    }
+}
@@ -7,12 +7,410 @@ pub fn parity_check(bytes: &[u8]) -> u8 {
         n_ones += ones;
     }
 
-    (n_ones % 2 == 0) as u8
+    n_ones.is_multiple_of(2) as u8
+}
+
+/// Why `hamming_decode` gave up on a buffer
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HammingError {
+    /// Every byte takes two codewords, an odd number of them cannot be whole
+    OddLength,
+    /// The codeword at this offset has two flipped bits, which can be detected but not corrected
+    DoubleError { offset: usize },
+}
+
+/// Bytes recovered by `hamming_decode`, with the number of codewords that needed a bit flipped
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HammingDecoded {
+    pub bytes: Vec<u8>,
+    pub corrected: usize,
+}
+
+/// Hamming(7,4) with an extra parity bit over the whole codeword (SECDED). Bits 1 to 7 hold
+/// the code, with the parity bits at 1, 2 and 4, and bit 0 makes the number of ones even
+fn hamming_encode_nibble(nibble: u8) -> u8 {
+    let d1 = nibble & 1;
+    let d2 = (nibble >> 1) & 1;
+    let d3 = (nibble >> 2) & 1;
+    let d4 = (nibble >> 3) & 1;
+
+    let p1 = d1 ^ d2 ^ d4;
+    let p2 = d1 ^ d3 ^ d4;
+    let p4 = d2 ^ d3 ^ d4;
+
+    let word = p1 << 1 | p2 << 2 | d1 << 3 | p4 << 4 | d2 << 5 | d3 << 6 | d4 << 7;
+    word | (word.count_ones() % 2) as u8
+}
+
+/// The nibble and whether a bit had to be flipped, `None` for two flipped bits
+fn hamming_decode_nibble(mut word: u8) -> Option<(u8, bool)> {
+    // XOR of the positions of the set bits is 0 for a valid codeword, or the flipped position
+    let syndrome = (1..8u8)
+        .filter(|position| word & (1 << position) != 0)
+        .fold(0, |syndrome, position| syndrome ^ position);
+    let odd = word.count_ones() % 2 == 1;
+
+    let corrected = match (syndrome, odd) {
+        (0, false) => false,
+        // A syndrome with even parity takes two flips
+        (_, false) => return None,
+        // One flip, a syndrome of 0 points at the overall parity bit
+        (syndrome, true) => {
+            word ^= 1 << syndrome;
+            true
+        }
+    };
+
+    let nibble =
+        (word >> 3) & 1 | ((word >> 5) & 1) << 1 | ((word >> 6) & 1) << 2 | (word >> 7) << 3;
+    Some((nibble, corrected))
+}
+
+/// Two codewords per byte, low nibble first. Any single flipped bit in a codeword is
+/// corrected by `hamming_decode` and any two are detected
+#[allow(dead_code)]
+pub fn hamming_encode(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|byte| {
+            [
+                hamming_encode_nibble(byte & 0x0f),
+                hamming_encode_nibble(byte >> 4),
+            ]
+        })
+        .collect()
+}
+
+#[allow(dead_code)]
+pub fn hamming_decode(codewords: &[u8]) -> Result<HammingDecoded, HammingError> {
+    if !codewords.len().is_multiple_of(2) {
+        return Err(HammingError::OddLength);
+    }
+
+    let mut decoded = HammingDecoded {
+        bytes: Vec::with_capacity(codewords.len() / 2),
+        corrected: 0,
+    };
+    let mut nibbles = [0; 2];
+    for (offset, pair) in codewords.chunks(2).enumerate() {
+        for (i, word) in pair.iter().enumerate() {
+            let (nibble, corrected) =
+                hamming_decode_nibble(*word).ok_or(HammingError::DoubleError {
+                    offset: offset * 2 + i,
+                })?;
+            nibbles[i] = nibble;
+            decoded.corrected += corrected as usize;
+        }
+        decoded.bytes.push(nibbles[0] | nibbles[1] << 4);
+    }
+    Ok(decoded)
+}
+
+/// Arithmetic in GF(256) built on the primitive polynomial x^8 + x^4 + x^3 + x^2 + 1, with
+/// 2 as the generator. Polynomials are stored highest degree first
+#[derive(Debug, Clone)]
+struct Gf256 {
+    /// Twice the 255 powers, so that products need no modulo
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Gf256 {
+    fn new() -> Gf256 {
+        let mut gf = Gf256 {
+            exp: [0; 512],
+            log: [0; 256],
+        };
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            gf.exp[i] = x as u8;
+            gf.log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        for i in 255..512 {
+            gf.exp[i] = gf.exp[i - 255];
+        }
+        gf
+    }
+
+    fn mul(&self, x: u8, y: u8) -> u8 {
+        if x == 0 || y == 0 {
+            return 0;
+        }
+        self.exp[self.log[x as usize] as usize + self.log[y as usize] as usize]
+    }
+
+    fn div(&self, x: u8, y: u8) -> u8 {
+        assert!(y != 0, "division by zero in GF(256)");
+        if x == 0 {
+            return 0;
+        }
+        self.exp[(self.log[x as usize] as usize + 255 - self.log[y as usize] as usize) % 255]
+    }
+
+    fn pow(&self, x: u8, power: usize) -> u8 {
+        self.exp[(self.log[x as usize] as usize * power) % 255]
+    }
+
+    fn inverse(&self, x: u8) -> u8 {
+        self.exp[255 - self.log[x as usize] as usize]
+    }
+
+    fn poly_scale(&self, p: &[u8], x: u8) -> Vec<u8> {
+        p.iter().map(|coef| self.mul(*coef, x)).collect()
+    }
+
+    fn poly_add(p: &[u8], q: &[u8]) -> Vec<u8> {
+        let len = p.len().max(q.len());
+        let mut sum = vec![0; len];
+        for (i, coef) in p.iter().enumerate() {
+            sum[i + len - p.len()] = *coef;
+        }
+        for (i, coef) in q.iter().enumerate() {
+            sum[i + len - q.len()] ^= coef;
+        }
+        sum
+    }
+
+    fn poly_mul(&self, p: &[u8], q: &[u8]) -> Vec<u8> {
+        let mut product = vec![0; p.len() + q.len() - 1];
+        for (i, a) in p.iter().enumerate() {
+            for (j, b) in q.iter().enumerate() {
+                product[i + j] ^= self.mul(*a, *b);
+            }
+        }
+        product
+    }
+
+    fn poly_eval(&self, p: &[u8], x: u8) -> u8 {
+        p.iter().fold(0, |y, coef| self.mul(y, x) ^ coef)
+    }
+
+    /// Remainder of `dividend` by a monic `divisor`
+    fn poly_rem(&self, dividend: &[u8], divisor: &[u8]) -> Vec<u8> {
+        let mut out = dividend.to_vec();
+        for i in 0..dividend.len() + 1 - divisor.len() {
+            let coef = out[i];
+            if coef != 0 {
+                for (j, d) in divisor.iter().enumerate().skip(1) {
+                    out[i + j] ^= self.mul(*d, coef);
+                }
+            }
+        }
+        out.split_off(dividend.len() + 1 - divisor.len())
+    }
+}
+
+/// A block had more corrupted symbols than its parity can correct
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uncorrectable;
+
+/// Bytes recovered by `ReedSolomon::decode`. Uncorrectable blocks are passed on as they were
+/// received, their numbers tell which ones to distrust
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReedSolomonDecoded {
+    pub bytes: Vec<u8>,
+    /// Symbols that were corrected, over all the blocks
+    pub corrected: usize,
+    pub uncorrectable: Vec<usize>,
+}
+
+/// Systematic Reed-Solomon code over GF(256). A block holds up to 255 symbols, the data followed
+/// by `parity` symbols, and any `parity / 2` corrupted symbols within it are corrected, however
+/// they are spread. A burst of flipped bits only corrupts the bytes it touches
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct ReedSolomon {
+    gf: Gf256,
+    parity: usize,
+    generator: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl ReedSolomon {
+    /// Panics unless `parity` is between 1 and 254, a block needs room for some data
+    pub fn new(parity: usize) -> ReedSolomon {
+        assert!(
+            (1..255).contains(&parity),
+            "parity has to be between 1 and 254 symbols, not {}",
+            parity
+        );
+        let gf = Gf256::new();
+        let mut generator = vec![1];
+        for i in 0..parity {
+            generator = gf.poly_mul(&generator, &[1, gf.pow(2, i)]);
+        }
+        ReedSolomon {
+            gf,
+            parity,
+            generator,
+        }
+    }
+
+    /// Corrupted symbols a block can take
+    pub fn capacity(&self) -> usize {
+        self.parity / 2
+    }
+
+    /// Data bytes in a full block
+    pub fn block_data_len(&self) -> usize {
+        255 - self.parity
+    }
+
+    /// `data` followed by its parity symbols, `data` has to fit in one block
+    pub fn encode_block(&self, data: &[u8]) -> Vec<u8> {
+        assert!(data.len() <= self.block_data_len());
+        let mut block = data.to_vec();
+        block.resize(data.len() + self.parity, 0);
+        let remainder = self.gf.poly_rem(&block, &self.generator);
+        block[data.len()..].copy_from_slice(&remainder);
+        block
+    }
+
+    /// Corrects `block` in place and returns how many symbols it changed. An uncorrectable
+    /// block is left as it is
+    pub fn decode_block(&self, block: &mut [u8]) -> Result<usize, Uncorrectable> {
+        if block.len() <= self.parity || block.len() > 255 {
+            return Err(Uncorrectable);
+        }
+        // With a leading 0, as Berlekamp-Massey and Forney expect them
+        let syndromes = self.syndromes(block);
+        if syndromes.iter().all(|s| *s == 0) {
+            return Ok(0);
+        }
+
+        let locator = self.error_locator(&syndromes)?;
+        let positions = self.error_positions(&locator, block.len())?;
+        // Too many errors can still look like a few, only a codeword is written back
+        let mut corrected = block.to_vec();
+        self.correct(&mut corrected, &syndromes, &positions);
+
+        if self.syndromes(&corrected).iter().any(|s| *s != 0) {
+            return Err(Uncorrectable);
+        }
+        block.copy_from_slice(&corrected);
+        Ok(positions.len())
+    }
+
+    /// Splits `data` into blocks and encodes each one, the output is the blocks back to back
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        data.chunks(self.block_data_len())
+            .flat_map(|chunk| self.encode_block(chunk))
+            .collect()
+    }
+
+    pub fn decode(&self, encoded: &[u8]) -> ReedSolomonDecoded {
+        let mut decoded = ReedSolomonDecoded {
+            bytes: Vec::with_capacity(encoded.len()),
+            corrected: 0,
+            uncorrectable: Vec::new(),
+        };
+        for (i, chunk) in encoded.chunks(255).enumerate() {
+            let mut block = chunk.to_vec();
+            match self.decode_block(&mut block) {
+                Ok(corrected) => decoded.corrected += corrected,
+                Err(Uncorrectable) => decoded.uncorrectable.push(i),
+            }
+            block.truncate(block.len().saturating_sub(self.parity));
+            decoded.bytes.extend_from_slice(&block);
+        }
+        decoded
+    }
+
+    fn syndromes(&self, block: &[u8]) -> Vec<u8> {
+        let mut syndromes = vec![0; self.parity + 1];
+        for (i, syndrome) in syndromes.iter_mut().skip(1).enumerate() {
+            *syndrome = self.gf.poly_eval(block, self.gf.pow(2, i));
+        }
+        syndromes
+    }
+
+    /// Berlekamp-Massey
+    fn error_locator(&self, syndromes: &[u8]) -> Result<Vec<u8>, Uncorrectable> {
+        let gf = &self.gf;
+        let mut locator = vec![1];
+        let mut old = vec![1];
+        for k in 1..=self.parity {
+            let mut delta = syndromes[k];
+            for j in 1..locator.len().min(k + 1) {
+                delta ^= gf.mul(locator[locator.len() - 1 - j], syndromes[k - j]);
+            }
+            old.push(0);
+            if delta != 0 {
+                if old.len() > locator.len() {
+                    let new = gf.poly_scale(&old, delta);
+                    old = gf.poly_scale(&locator, gf.inverse(delta));
+                    locator = new;
+                }
+                locator = Gf256::poly_add(&locator, &gf.poly_scale(&old, delta));
+            }
+        }
+
+        let leading = locator.iter().take_while(|coef| **coef == 0).count();
+        let locator = locator.split_off(leading);
+        if (locator.len() - 1) * 2 > self.parity {
+            return Err(Uncorrectable);
+        }
+        Ok(locator)
+    }
+
+    /// Chien search, the roots of the locator give the corrupted positions
+    fn error_positions(&self, locator: &[u8], len: usize) -> Result<Vec<usize>, Uncorrectable> {
+        let reversed: Vec<u8> = locator.iter().rev().copied().collect();
+        let positions: Vec<usize> = (0..len)
+            .filter(|i| self.gf.poly_eval(&reversed, self.gf.pow(2, *i)) == 0)
+            .map(|i| len - 1 - i)
+            .collect();
+        // Roots past the end of a short block point at symbols that do not exist
+        if positions.len() != locator.len() - 1 {
+            return Err(Uncorrectable);
+        }
+        Ok(positions)
+    }
+
+    /// Forney, works out the value of each error and removes it
+    fn correct(&self, block: &mut [u8], syndromes: &[u8], positions: &[usize]) {
+        let gf = &self.gf;
+        let powers: Vec<usize> = positions.iter().map(|p| block.len() - 1 - p).collect();
+
+        let mut locator = vec![1];
+        for power in &powers {
+            locator = gf.poly_mul(&locator, &[gf.pow(2, *power), 1]);
+        }
+        let reversed: Vec<u8> = syndromes.iter().rev().copied().collect();
+        let mut divisor = vec![0; locator.len() + 1];
+        divisor[0] = 1;
+        let evaluator = gf.poly_rem(&gf.poly_mul(&reversed, &locator), &divisor);
+
+        let roots: Vec<u8> = powers.iter().map(|power| gf.pow(2, *power)).collect();
+        for (i, root) in roots.iter().enumerate() {
+            let inverse = gf.inverse(*root);
+            let derivative = roots
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .fold(1, |product, (_, other)| {
+                    gf.mul(product, 1 ^ gf.mul(inverse, *other))
+                });
+            let y = gf.mul(*root, gf.poly_eval(&evaluator, inverse));
+            block[positions[i]] ^= gf.div(y, derivative);
+        }
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::utils::check_functions::parity_check;
+    use crate::utils::check_functions::{
+        hamming_decode, hamming_encode, parity_check, HammingDecoded, HammingError, ReedSolomon,
+        Uncorrectable,
+    };
 
     #[test]
     pub fn test_parity_check() {
@@ -22,4 +420,129 @@ pub mod tests {
         let abcd = b"abcd";
         assert_eq!(parity_check(abcd), 0);
     }
+
+    #[test]
+    pub fn test_hamming_corrects_every_single_bit() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let encoded = hamming_encode(&bytes);
+        assert_eq!(encoded.len(), bytes.len() * 2);
+        assert_eq!(
+            hamming_decode(&encoded),
+            Ok(HammingDecoded {
+                bytes: bytes.clone(),
+                corrected: 0
+            })
+        );
+
+        for i in 0..encoded.len() {
+            for bit in 0..8 {
+                let mut damaged = encoded.clone();
+                damaged[i] ^= 1 << bit;
+                let decoded = hamming_decode(&damaged).unwrap();
+                assert_eq!(decoded.bytes, bytes);
+                assert_eq!(decoded.corrected, 1);
+            }
+        }
+
+        // One flipped bit in every codeword at once is still fine
+        let damaged: Vec<u8> = encoded
+            .iter()
+            .enumerate()
+            .map(|(i, word)| word ^ 1 << (i % 8))
+            .collect();
+        assert_eq!(hamming_decode(&damaged).unwrap().bytes, bytes);
+    }
+
+    #[test]
+    pub fn test_hamming_detects_double_errors() {
+        let encoded = hamming_encode(b"vlad");
+        for first in 0..8 {
+            for second in first + 1..8 {
+                let mut damaged = encoded.clone();
+                damaged[5] ^= 1 << first | 1 << second;
+                assert_eq!(
+                    hamming_decode(&damaged),
+                    Err(HammingError::DoubleError { offset: 5 })
+                );
+            }
+        }
+        assert_eq!(hamming_decode(&encoded[1..]), Err(HammingError::OddLength));
+    }
+
+    /// Deterministic bytes, so that failures can be replayed
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 + i / 7) as u8).collect()
+    }
+
+    #[test]
+    pub fn test_reed_solomon_corrects_bursts() {
+        let rs = ReedSolomon::new(16);
+        assert_eq!(rs.capacity(), 8);
+        let data = pattern(1000);
+        let encoded = rs.encode(&data);
+        // Four full blocks of 239 bytes and one of 44, each with 16 parity symbols
+        assert_eq!(encoded.len(), 1000 + 5 * 16);
+        let clean = rs.decode(&encoded);
+        assert_eq!(clean.bytes, data);
+        assert_eq!(clean.corrected, 0);
+
+        // A burst of 8 bytes wherever it lands in a block, parity included
+        for start in (0..255 - 8).step_by(13) {
+            let mut damaged = encoded.clone();
+            for byte in &mut damaged[start..start + 8] {
+                *byte ^= 0xa5;
+            }
+            let decoded = rs.decode(&damaged);
+            assert_eq!(decoded.bytes, data);
+            assert_eq!(decoded.corrected, 8);
+            assert!(decoded.uncorrectable.is_empty());
+        }
+
+        // Scattered errors in every block, the short last one too
+        let mut damaged = encoded.clone();
+        for i in (0..damaged.len()).step_by(37) {
+            damaged[i] = !damaged[i];
+        }
+        let decoded = rs.decode(&damaged);
+        assert_eq!(decoded.bytes, data);
+        assert!(decoded.uncorrectable.is_empty());
+
+        let mut block = rs.encode_block(b"vlad");
+        block[0] = 0;
+        block[19] = 0;
+        assert_eq!(rs.decode_block(&mut block), Ok(2));
+        assert_eq!(&block[..4], b"vlad");
+    }
+
+    #[test]
+    pub fn test_reed_solomon_reports_uncorrectable_blocks() {
+        let rs = ReedSolomon::new(4);
+        let data = pattern(600);
+        let mut damaged = rs.encode(&data);
+
+        // Two errors fit the second block, three are too many for the first
+        damaged[10] ^= 1;
+        damaged[100] ^= 2;
+        damaged[200] ^= 4;
+        damaged[300] ^= 8;
+        damaged[400] ^= 16;
+        let decoded = rs.decode(&damaged);
+        assert_eq!(decoded.uncorrectable, vec![0]);
+        assert_eq!(decoded.corrected, 2);
+        assert_eq!(decoded.bytes.len(), data.len());
+        assert_ne!(decoded.bytes[..251], data[..251]);
+        assert_eq!(decoded.bytes[251..], data[251..]);
+
+        let mut block = rs.encode_block(b"impaler");
+        block[1..4].copy_from_slice(&[0; 3]);
+        let received = block.clone();
+        assert_eq!(rs.decode_block(&mut block), Err(Uncorrectable));
+        assert_eq!(block, received);
+
+        // Here the correction goes through and only the check of the result catches it
+        let mut block = vec![202, 31, 62, 9, 124, 155, 7, 218, 249, 24, 227, 165, 94, 47];
+        let received = block.clone();
+        assert_eq!(rs.decode_block(&mut block), Err(Uncorrectable));
+        assert_eq!(block, received);
+    }
 }